@@ -0,0 +1,91 @@
+// address
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+const VERSION: u8 = 0x00;
+pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
+// RIPEMD-160 output
+const PUB_KEY_HASH_LEN: usize = 20;
+// version + pub_key_hash + checksum
+const ADDRESS_LEN: usize = 1 + PUB_KEY_HASH_LEN + ADDRESS_CHECK_SUM_LEN;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Address {
+    pub_key_hash: Vec<u8>,
+}
+
+impl Address {
+    pub fn from_pub_key_hash(pub_key_hash: &[u8]) -> Address {
+        Address {
+            pub_key_hash: pub_key_hash.to_vec(),
+        }
+    }
+
+    pub fn get_pub_key_hash(&self) -> &[u8] {
+        self.pub_key_hash.as_slice()
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(address: &str) -> Result<Address, AddressError> {
+        let payload = crate::base58_decode(address)
+            .map_err(|e| AddressError::InvalidBase58(e.to_string()))?;
+        if payload.len() != ADDRESS_LEN {
+            return Err(AddressError::InvalidLength(payload.len()));
+        }
+        let (body, actual_checksum) = payload.split_at(1 + PUB_KEY_HASH_LEN);
+        if body[0] != VERSION {
+            return Err(AddressError::UnknownVersion(body[0]));
+        }
+        if checksum(body) != actual_checksum {
+            return Err(AddressError::ChecksumMismatch);
+        }
+        Ok(Address::from_pub_key_hash(&body[1..]))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut payload: Vec<u8> = vec![];
+        payload.push(VERSION);
+        payload.extend(self.pub_key_hash.as_slice());
+        let checksum = checksum(payload.as_slice());
+        payload.extend(checksum.as_slice());
+        write!(f, "{}", crate::base58_encode(payload.as_slice()))
+    }
+}
+
+// Why a string could not be parsed as an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    InvalidBase58(String),
+    InvalidLength(usize),
+    UnknownVersion(u8),
+    ChecksumMismatch,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddressError::InvalidBase58(msg) => write!(f, "invalid base58: {}", msg),
+            AddressError::InvalidLength(len) => {
+                write!(f, "decoded to {} bytes, expected {}", len, ADDRESS_LEN)
+            }
+            AddressError::UnknownVersion(version) => {
+                write!(f, "unknown version byte {:#04x}", version)
+            }
+            AddressError::ChecksumMismatch => write!(f, "checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+fn checksum(payload: &[u8]) -> Vec<u8> {
+    let first_sha = crate::sha256_digest(payload);
+    let second_sha = crate::sha256_digest(first_sha.as_slice());
+    second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
+}
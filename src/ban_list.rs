@@ -0,0 +1,108 @@
+use crate::{Blockchain, BlockchainError};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+const BANNED_TREE: &str = "banned";
+
+// Peers reaching this ban score are disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Banned peer IPs ( K -> IP, V -> banned until, milliseconds ). Peers are
+/// banned by the IP they connect from, the addresses they announce are theirs
+/// to choose.
+pub struct BanList {
+    blockchain: Blockchain,
+}
+
+impl BanList {
+    pub fn new(blockchain: Blockchain) -> BanList {
+        BanList { blockchain }
+    }
+
+    pub fn ban(&self, ip: IpAddr, until: i64) -> Result<(), BlockchainError> {
+        let db = self.blockchain.get_db();
+        let banned_tree = db.open_tree(BANNED_TREE)?;
+        banned_tree.insert(ip.to_string(), until.to_be_bytes().to_vec())?;
+        Ok(())
+    }
+
+    // Expired bans are lifted on the first lookup after they end
+    pub fn is_banned(&self, ip: IpAddr) -> Result<bool, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let banned_tree = db.open_tree(BANNED_TREE)?;
+        let ip = ip.to_string();
+        let until = match banned_tree.get(ip.as_str())? {
+            Some(until) => decode_time(until.as_ref())?,
+            None => return Ok(false),
+        };
+        if until <= crate::current_timestamp() {
+            banned_tree.remove(ip.as_str())?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    // Current bans with the time they end
+    pub fn get_bans(&self) -> Result<Vec<(String, i64)>, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let banned_tree = db.open_tree(BANNED_TREE)?;
+        let now = crate::current_timestamp();
+        let mut bans = vec![];
+        for item in banned_tree.iter() {
+            let (ip, until) = item?;
+            let until = decode_time(until.as_ref())?;
+            if until > now {
+                bans.push((String::from_utf8(ip.to_vec())?, until));
+            }
+        }
+        Ok(bans)
+    }
+
+    // Lifts the ban of one IP, or of every IP without one.
+    // Returns the number of lifted bans.
+    pub fn clear(&self, ip: Option<IpAddr>) -> Result<usize, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let banned_tree = db.open_tree(BANNED_TREE)?;
+        match ip {
+            Some(ip) => Ok(banned_tree.remove(ip.to_string())?.map_or(0, |_| 1)),
+            None => {
+                let count = banned_tree.len();
+                banned_tree.clear()?;
+                Ok(count)
+            }
+        }
+    }
+}
+
+fn decode_time(bytes: &[u8]) -> Result<i64, BlockchainError> {
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| BlockchainError::CorruptData(String::from("invalid ban time")))?;
+    Ok(i64::from_be_bytes(bytes))
+}
+
+/// Misbehaviour of peers during this run ( K -> IP, V -> ban score )
+pub struct BanScores {
+    inner: RwLock<HashMap<IpAddr, u32>>,
+}
+
+impl BanScores {
+    pub fn new() -> BanScores {
+        BanScores {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Adds to the score of the peer and returns its new score
+    pub fn add(&self, ip: IpAddr, score: u32) -> u32 {
+        let mut inner = self.inner.write().unwrap();
+        let total = inner.entry(ip).or_insert(0);
+        *total = total.saturating_add(score);
+        *total
+    }
+
+    pub fn remove(&self, ip: IpAddr) {
+        self.inner.write().unwrap().remove(&ip);
+    }
+}
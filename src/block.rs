@@ -1,39 +1,116 @@
-use crate::{ProofOfWork, Transaction, BlockchainError};
+use crate::merkle::{self, MerkleProof};
+use crate::{BlockchainError, ProofOfWork, Transaction};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
 
+// Version of the block header format
+const BLOCK_VERSION: u32 = 1;
+
+// Most bytes a serialized block may take
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// The part of a block that is hashed and mined; it commits to the
+/// transactions through the merkle root
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockHeader {
+    version: u32,
+    pre_block_hash: String,
+    merkle_root: Vec<u8>,
+    timestamp: i64,
+    bits: u32,
+    nonce: i64,
+    height: usize,
+}
+
+impl BlockHeader {
+    // Hex encoded hash of the header, which is also the block hash
+    pub fn get_hash(&self) -> String {
+        ProofOfWork::new_proof_of_work(self.clone()).hash()
+    }
+
+    pub fn is_valid_pow(&self) -> bool {
+        ProofOfWork::new_proof_of_work(self.clone()).validate()
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_pre_block_hash(&self) -> String {
+        self.pre_block_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.merkle_root.as_slice()
+    }
+
+    pub fn get_timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<BlockHeader, BlockchainError> {
+        bincode::deserialize(bytes)
+            .map_err(|e| BlockchainError::DeserializationError(e.to_string()))
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: i64,                
-    pre_block_hash: String,         
+    header: BlockHeader,
     hash: String,                   
     transactions: Vec<Transaction>, 
-    nonce: i64,                     
-    height: usize,                  
 }
 
 impl Block {
    
-    pub fn new_block(pre_block_hash: String, transactions: &[Transaction], height: usize) -> Block {
+    pub fn new_block(
+        pre_block_hash: String,
+        transactions: &[Transaction],
+        height: usize,
+        bits: u32,
+    ) -> Block {
         let mut block = Block {
-            timestamp: crate::current_timestamp(),
-            pre_block_hash,
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                pre_block_hash,
+                merkle_root: vec![],
+                timestamp: crate::current_timestamp(),
+                bits,
+                nonce: 0,
+                height,
+            },
             hash: String::new(),
             transactions: transactions.to_vec(),
-            nonce: 0,
-            height,
         };
+        block.header.merkle_root = block.compute_merkle_root();
        
-        let pow = ProofOfWork::new_proof_of_work(block.clone());
+        let pow = ProofOfWork::new_proof_of_work(block.header.clone());
         let (nonce, hash) = pow.run();
-        block.nonce = nonce;
+        block.header.nonce = nonce;
         block.hash = hash;
         block
     }
 
   
-    pub fn deserialize(bytes: &[u8]) -> Block {
-        bincode::deserialize(bytes).unwrap()
+    pub fn deserialize(bytes: &[u8]) -> Result<Block, BlockchainError> {
+        bincode::deserialize(bytes)
+            .map_err(|e| BlockchainError::DeserializationError(e.to_string()))
     }
 
    
@@ -44,23 +121,41 @@ impl Block {
 
     pub fn generate_genesis_block(transaction: &Transaction) -> Block {
         let transactions = vec![transaction.clone()];
-        Block::new_block(String::from("None"), &transactions, 0)
+        Block::new_block(
+            String::from("None"),
+            &transactions,
+            0,
+            ProofOfWork::initial_bits(),
+        )
+    }
+
+    fn txids(&self) -> Vec<Vec<u8>> {
+        self.transactions.iter().map(|tx| tx.get_id_bytes()).collect()
     }
 
-    pub fn hash_transactions(&self) -> Vec<u8> {
-        let mut txhashs = vec![];
-        for transaction in &self.transactions {
-            txhashs.extend(transaction.get_id());
-        }
-        crate::sha256_digest(txhashs.as_slice())
+    // Merkle root of the transaction ids, as it should be in the header
+    pub fn compute_merkle_root(&self) -> Vec<u8> {
+        merkle::merkle_root(self.txids().as_slice())
+    }
+
+    pub fn get_merkle_root(&self) -> &[u8] {
+        self.header.get_merkle_root()
+    }
+
+    pub fn merkle_proof(&self, txid: &[u8]) -> Option<MerkleProof> {
+        merkle::merkle_proof(self.txids().as_slice(), txid)
     }
 
     pub fn get_transactions(&self) -> &[Transaction] {
         self.transactions.as_slice()
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_pre_block_hash(&self) -> String {
-        self.pre_block_hash.clone()
+        self.header.get_pre_block_hash()
     }
 
     pub fn get_hash(&self) -> &str {
@@ -71,12 +166,25 @@ impl Block {
         self.hash.as_bytes().to_vec()
     }
 
+    // The stored hash must be the header hash and meet the target
+    pub fn is_valid_pow(&self) -> bool {
+        self.header.get_hash() == self.hash && self.header.is_valid_pow()
+    }
+
     pub fn get_timestamp(&self) -> i64 {
-        self.timestamp
+        self.header.get_timestamp()
     }
 
     pub fn get_height(&self) -> usize {
-        self.height
+        self.header.get_height()
+    }
+
+    pub fn get_nonce(&self) -> i64 {
+        self.header.get_nonce()
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
     }
 }
 
@@ -1,65 +1,134 @@
-use crate::transactions::{TXOutput, Transaction};
-use crate::block::Block;
+use crate::transactions::{sum_values, TXOutput, Transaction, SUBSIDY};
+use crate::block::{Block, BlockHeader, MAX_BLOCK_SIZE};
+use crate::tx_index::TX_INDEX_TREE;
+use crate::utxo_set::{UNDO_TREE, UTXO_TREE};
+use crate::{Address, BlockchainError, ProofOfWork, TxIndex, UTXOSet, UTXOView, GLOBAL_CONFIG};
 use data_encoding::HEXLOWER;
+use log::info;
+use num_bigint::BigInt;
 use sled::transaction::TransactionResult;
-use sled::{Db, Tree};
-use std::collections::HashMap;
+use sled::{Db, Transactional, Tree};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::current_dir;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 
 const TIP_BLOCK_HASH_KEY: &str = "tip_block_hash";
 const BLOCKS_TREE: &str = "blocks";
+const CHAIN_WORK_TREE: &str = "chainwork";
+// ( K -> height, V -> block hash ) of the active chain
+const HEIGHTS_TREE: &str = "heights";
+// ( K -> block hash, V -> BlockHeader ) of every valid header, with or without a body
+const HEADERS_TREE: &str = "headers";
+const BEST_HEADER_KEY: &str = "best_header_hash";
+// ( K -> block hash, V -> empty ) of blocks found invalid and their descendants
+const FAILED_TREE: &str = "failed";
+// Version of the stored layout, kept in the default tree. Databases written
+// before it was recorded are format 0.
+const DB_FORMAT_KEY: &str = "db_format";
+const DB_FORMAT: u32 = 1;
+
+// Blocks may be at most two hours ahead of the local clock (milliseconds)
+const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60 * 1000;
+// Header failures an honest peer can run into, with a clock ahead of ours or
+// another view of the expected difficulty
+const FUTURE_TIMESTAMP: &str = "timestamp is too far in the future";
+const UNEXPECTED_BITS: &str = "bits do not match the expected difficulty";
+// Number of blocks the median time past is computed over
+const MEDIAN_TIME_SPAN: usize = 11;
 
 #[derive(Clone)]
 pub struct Blockchain {
     tip_hash: Arc<RwLock<String>>, // hash of last block
+    chain_lock: Arc<Mutex<()>>,    // serialises changes to the active chain
     db: Db,
 }
 
 impl Blockchain {
     
-    pub fn create_blockchain(genesis_address: &str) -> Blockchain {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+    pub fn create_blockchain(genesis_address: &Address) -> Result<Blockchain, BlockchainError> {
+        Self::create_in(open_db()?, genesis_address)
+    }
 
-        let data = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap();
+    pub(crate) fn create_in(
+        db: Db,
+        genesis_address: &Address,
+    ) -> Result<Blockchain, BlockchainError> {
+        // A database in another format can not be read, the chain starts over
+        if get_db_format(&db)? != DB_FORMAT {
+            for name in db.tree_names() {
+                if name != db.name() {
+                    db.drop_tree(name)?;
+                }
+            }
+            db.clear()?;
+            db.insert(DB_FORMAT_KEY, &DB_FORMAT.to_be_bytes())?;
+        }
+        let blocks_tree = db.open_tree(BLOCKS_TREE)?;
+
+        let data = blocks_tree.get(TIP_BLOCK_HASH_KEY)?;
         
-        let tip_hash;
-        if data.is_none() {
-            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address);
-            let block = Block::generate_genesis_block(&coinbase_tx);
-            Self::update_blocks_tree(&blocks_tree, &block);
-            tip_hash = String::from(block.get_hash());
+        let tip_hash = if let Some(data) = data {
+            String::from_utf8(data.to_vec())?
         } else {
-            tip_hash = String::from_utf8(data.unwrap().to_vec()).unwrap();
-        }
+            let coinbase_tx = Transaction::new_coinbase_tx(genesis_address, 0);
+            let block = Block::generate_genesis_block(&coinbase_tx);
+            Self::update_blocks_tree(&blocks_tree, &block)?;
+            String::from(block.get_hash())
+        };
         
-        Blockchain {
+        let blockchain = Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            chain_lock: Arc::new(Mutex::new(())),
             db,
-        }
+        };
+        blockchain.ensure_height_index()?;
+        Ok(blockchain)
     }
 
-    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) {
+    fn update_blocks_tree(blocks_tree: &Tree, block: &Block) -> Result<(), BlockchainError> {
         let block_hash = block.get_hash();
-        let _: TransactionResult<(), ()> = blocks_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block_hash, block.clone());
-            let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash);
+        let result: TransactionResult<(), BlockchainError> = blocks_tree.transaction(|tx_db| {
+            tx_db.insert(block_hash, block.clone())?;
+            tx_db.insert(TIP_BLOCK_HASH_KEY, block_hash)?;
             Ok(())
         });
+        Ok(result?)
     }
 
    
-    pub fn new_blockchain() -> Blockchain {
-        let db = sled::open(current_dir().unwrap().join("data")).unwrap();
-        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_bytes = blocks_tree.get(TIP_BLOCK_HASH_KEY).unwrap().unwrap();
-        let tip_hash = String::from_utf8(tip_bytes.to_vec()).unwrap();
+    pub fn new_blockchain() -> Result<Blockchain, BlockchainError> {
+        let db = open_db()?;
+        check_db_format(&db)?;
+        let blocks_tree = db.open_tree(BLOCKS_TREE)?;
+        let tip_bytes = blocks_tree.get(TIP_BLOCK_HASH_KEY)?.ok_or_else(|| {
+            BlockchainError::NotFoundError(String::from("no blockchain found, create one first"))
+        })?;
+        let tip_hash = String::from_utf8(tip_bytes.to_vec())?;
         
-        Blockchain {
+        let blockchain = Blockchain {
             tip_hash: Arc::new(RwLock::new(tip_hash)),
+            chain_lock: Arc::new(Mutex::new(())),
             db,
+        };
+        blockchain.ensure_height_index()?;
+        Ok(blockchain)
+    }
+
+    // Rebuilds the height index when it does not end at the current tip, for
+    // new chains and data directories created before the index existed
+    fn ensure_height_index(&self) -> Result<(), BlockchainError> {
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        let tip_block = self.get_tip_block()?;
+        let indexed_hash = height_tree.get(height_key(tip_block.get_height()))?;
+        if indexed_hash.is_some_and(|hash| hash.as_ref() == tip_block.get_hash().as_bytes()) {
+            return Ok(());
+        }
+        height_tree.clear()?;
+        for block in self.iterator() {
+            let block = block?;
+            height_tree.insert(height_key(block.get_height()), block.get_hash())?;
         }
+        Ok(())
     }
 
     pub fn get_db(&self) -> &Db {
@@ -74,153 +143,794 @@ impl Blockchain {
         *self.tip_hash.write().unwrap() = String::from(new_tip_hash);
     }
   
-    pub fn mine_block(&self, transactions: &[Transaction]) -> Block {
+    // Transactions may spend outputs of those before them in the block
+    pub fn mine_block(&self, transactions: &[Transaction]) -> Result<Block, BlockchainError> {
+        let mut view = UTXOView::new(UTXOSet::new(self.clone()));
         for transaction in transactions {
-            if !transaction.verify(self) {
-                panic!("ERROR: Invalid transaction found");
+            if !transaction.verify(&view)? {
+                return Err(BlockchainError::InvalidTransaction(format!(
+                    "{} has an invalid signature",
+                    HEXLOWER.encode(transaction.get_id())
+                )));
             }
+            view.apply(transaction);
         }
         
-        let best_height = self.get_best_height();
-        let block = Block::new_block(self.get_tip_hash(), transactions, best_height + 1);
-        let block_hash = block.get_hash();
+        let tip_block = self.get_tip_block()?;
+        let bits = self.get_next_bits(tip_block.get_header())?;
+        let block = Block::new_block(
+            String::from(tip_block.get_hash()),
+            transactions,
+            tip_block.get_height() + 1,
+            bits,
+        );
 
-        let blocks_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        
-        Self::update_blocks_tree(&blocks_tree, &block);
-        self.set_tip_hash(block_hash);
-        
-        block
+        self.add_block(&block)?;
+        Ok(block)
     }
 
     pub fn iterator(&self) -> BlockchainIterator {
         BlockchainIterator::new(self.get_tip_hash(), self.db.clone())
     }
 
-   // ( K -> txid_hex, V -> Vec<TXOutput )
-    pub fn find_utxo(&self) -> HashMap<String, Vec<TXOutput>> {
-        let mut utxo: HashMap<String, Vec<TXOutput>> = HashMap::new();
+   // ( K -> txid_hex, V -> ( K -> vout, V -> TXOutput ) )
+    pub fn find_utxo(
+        &self,
+    ) -> Result<HashMap<String, BTreeMap<usize, TXOutput>>, BlockchainError> {
+        let mut utxo: HashMap<String, BTreeMap<usize, TXOutput>> = HashMap::new();
         let mut spent_txos: HashMap<String, Vec<usize>> = HashMap::new();
 
-        let mut iterator = self.iterator();
-        loop {
-            let result = iterator.next();
-            if let Some(block) = result {
-                'outer: for tx in block.get_transactions() {
-                    let txid_hex = HEXLOWER.encode(tx.get_id());
-                    for (idx, out) in tx.get_vout().iter().enumerate() {
-                        
-                        if let Some(outs) = spent_txos.get(txid_hex.as_str()) {
-                            for spend_out_idx in outs {
-                                if idx.eq(spend_out_idx) {
-                                    continue 'outer;
-                                }
-                            }
-                        }
-                        if utxo.contains_key(txid_hex.as_str()) {
-                            utxo.get_mut(txid_hex.as_str()).unwrap().push(out.clone());
-                        } else {
-                            utxo.insert(txid_hex.clone(), vec![out.clone()]);
-                        }
-                    }
-                    if tx.is_coinbase() {
+        for block in self.iterator() {
+            let block = block?;
+            // Backwards, so that spends inside the block are seen first too
+            for tx in block.get_transactions().iter().rev() {
+                let txid_hex = HEXLOWER.encode(tx.get_id());
+                for (idx, out) in tx.get_vout().iter().enumerate() {
+                    if let Some(outs) = spent_txos.get(txid_hex.as_str())
+                        && outs.contains(&idx)
+                    {
                         continue;
                     }
-                 
-                    for txin in tx.get_vin() {
-                        let txid_hex = HEXLOWER.encode(txin.get_txid());
-                        if spent_txos.contains_key(txid_hex.as_str()) {
-                            spent_txos
-                                .get_mut(txid_hex.as_str())
-                                .unwrap()
-                                .push(txin.get_vout());
-                        } else {
-                            spent_txos.insert(txid_hex, vec![txin.get_vout()]);
-                        }
+                    utxo.entry(txid_hex.clone())
+                        .or_default()
+                        .insert(idx, out.clone());
+                }
+                if tx.is_coinbase() {
+                    continue;
+                }
+
+                for txin in tx.get_vin() {
+                    let txid_hex = HEXLOWER.encode(txin.get_txid());
+                    if spent_txos.contains_key(txid_hex.as_str()) {
+                        spent_txos
+                            .get_mut(txid_hex.as_str())
+                            .unwrap()
+                            .push(txin.get_vout());
+                    } else {
+                        spent_txos.insert(txid_hex, vec![txin.get_vout()]);
                     }
                 }
-            } else {
-                break;
             }
         }
-        utxo
+        Ok(utxo)
     }
 
    
-    pub fn find_transaction(&self, txid: &[u8]) -> Option<Transaction> {
-        let mut iterator = self.iterator();
-        loop {
-            let result = iterator.next();
-            if let Some(block) = result {
-                for transaction in block.get_transactions() {
-                    if txid.eq(transaction.get_id()) {
-                        return Some(transaction.clone());
-                    }
+    pub fn find_transaction(&self, txid: &[u8]) -> Result<Option<Transaction>, BlockchainError> {
+        let tx_index = TxIndex::new(self.clone());
+        if tx_index.is_enabled()? {
+            return tx_index.find_transaction(txid);
+        }
+        for block in self.iterator() {
+            for transaction in block?.get_transactions() {
+                if txid.eq(transaction.get_id()) {
+                    return Ok(Some(transaction.clone()));
                 }
+            }
+        }
+        Ok(None)
+    }
+
+  
+    // Stores a valid block and makes the chain with the most cumulative work
+    // the active one, reorganising the UTXO set when a side branch overtakes it
+    pub fn add_block(&self, block: &Block) -> Result<ChainUpdate, BlockchainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
+        if block_tree.get(block.get_hash())?.is_some() {
+            return Ok(ChainUpdate::default());
+        }
+        self.validate_block(block)?;
+        block_tree.insert(block.get_hash(), block.serialize())?;
+        self.store_header(block.get_hash(), block.get_header())?;
+
+        let tip_block = self.get_tip_block()?;
+        if self.get_chain_work(block.get_hash())? <= self.get_chain_work(tip_block.get_hash())? {
+            return Ok(ChainUpdate::default());
+        }
+        if block.get_pre_block_hash() == tip_block.get_hash() {
+            self.connect_block(block)?;
+            return Ok(ChainUpdate {
+                disconnected: vec![],
+                connected: vec![block.clone()],
+            });
+        }
+        self.reorganize(tip_block, block)
+    }
+
+    // Stores a header whose proof of work and difficulty are valid, so its
+    // body can be downloaded later. Returns false when it was already known.
+    pub fn add_header(&self, header: &BlockHeader) -> Result<bool, BlockchainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let hash = header.get_hash();
+        if self.get_header(hash.as_str())?.is_some() {
+            return Ok(false);
+        }
+        self.validate_header(hash.as_str(), header)?;
+        self.store_header(hash.as_str(), header)?;
+        Ok(true)
+    }
+
+    fn store_header(&self, hash: &str, header: &BlockHeader) -> Result<(), BlockchainError> {
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        header_tree.insert(hash, header.serialize())?;
+        let best_hash = self.get_best_header_hash()?;
+        if self.get_chain_work(hash)? > self.get_chain_work(best_hash.as_str())? {
+            header_tree.insert(BEST_HEADER_KEY, hash)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_header(&self, hash: &str) -> Result<Option<BlockHeader>, BlockchainError> {
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        if let Some(header_bytes) = header_tree.get(hash)? {
+            return Ok(Some(bincode::deserialize(header_bytes.as_ref())?));
+        }
+        // Blocks stored before headers were tracked separately
+        Ok(self
+            .get_block(hash.as_bytes())?
+            .map(|block| block.get_header().clone()))
+    }
+
+    // Hash of the header chain with the most work, which may be ahead of the tip
+    pub fn get_best_header_hash(&self) -> Result<String, BlockchainError> {
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        match header_tree.get(BEST_HEADER_KEY)? {
+            Some(hash) => Ok(String::from_utf8(hash.to_vec())?),
+            None => Ok(self.get_tip_hash()),
+        }
+    }
+
+    // Hashes of the best header chain blocks whose bodies are not stored yet,
+    // in height order
+    pub fn get_missing_block_hashes(&self) -> Result<Vec<Vec<u8>>, BlockchainError> {
+        let mut missing = vec![];
+        let mut hash = self.get_best_header_hash()?;
+        while self.get_block(hash.as_bytes())?.is_none() {
+            let header = match self.get_header(hash.as_str())? {
+                Some(header) => header,
+                None => break,
+            };
+            missing.push(hash.into_bytes());
+            hash = header.get_pre_block_hash();
+        }
+        missing.reverse();
+        Ok(missing)
+    }
+
+    // Switches the active chain from `old_tip` to the branch ending at `new_tip`
+    fn reorganize(&self, old_tip: Block, new_tip: &Block) -> Result<ChainUpdate, BlockchainError> {
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        let mut old_block = old_tip;
+        let mut new_block = new_tip.clone();
+        while old_block.get_hash() != new_block.get_hash() {
+            if old_block.get_height() >= new_block.get_height() {
+                let parent = self.get_parent(&old_block)?;
+                disconnected.push(old_block);
+                old_block = parent;
             } else {
+                let parent = self.get_parent(&new_block)?;
+                connected.push(new_block);
+                new_block = parent;
+            }
+        }
+        connected.reverse();
+        info!(
+            "Reorganising at fork point {}: {} blocks out, {} blocks in",
+            old_block.get_hash(),
+            disconnected.len(),
+            connected.len()
+        );
+
+        for block in &disconnected {
+            self.disconnect_block(block)?;
+        }
+        for (idx, block) in connected.iter().enumerate() {
+            let result = self.check_spends(block).and_then(|_| self.connect_block(block));
+            if let Err(e) = result {
+                // Restore the previous active chain before reporting the error
+                for block in connected[..idx].iter().rev() {
+                    self.disconnect_block(block)?;
+                }
+                for block in disconnected.iter().rev() {
+                    self.connect_block(block)?;
+                }
+                self.forget_branch(block.get_hash())?;
+                return Err(e);
+            }
+        }
+        Ok(ChainUpdate {
+            disconnected,
+            connected,
+        })
+    }
+
+    // Removes a block found invalid along with every stored block and header
+    // building on it, remembers them as failed so they are not stored again,
+    // and points the best header back at a valid chain
+    fn forget_branch(&self, hash: &str) -> Result<(), BlockchainError> {
+        let failed_tree = self.db.open_tree(FAILED_TREE)?;
+        for hash in self.remove_branches(vec![String::from(hash)])? {
+            info!("Forgetting invalid block {}", hash);
+            failed_tree.insert(hash.as_str(), vec![])?;
+        }
+        self.reset_best_header()
+    }
+
+    // Removes the blocks and headers of `roots` and of everything building on
+    // them, none of which may be on the active chain. Returns the removed hashes.
+    fn remove_branches(&self, roots: Vec<String>) -> Result<Vec<String>, BlockchainError> {
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let work_tree = self.db.open_tree(CHAIN_WORK_TREE)?;
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        // ( K -> parent hash, V -> hashes of its children )
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for item in header_tree.iter() {
+            let (key, value) = item?;
+            if key.as_ref() == BEST_HEADER_KEY.as_bytes() {
+                continue;
+            }
+            let header = BlockHeader::deserialize(value.as_ref())?;
+            let child = String::from_utf8(key.to_vec())?;
+            children.entry(header.get_pre_block_hash()).or_default().push(child);
+        }
+
+        let mut removed = vec![];
+        let mut pending = roots;
+        while let Some(hash) = pending.pop() {
+            block_tree.remove(hash.as_str())?;
+            work_tree.remove(hash.as_str())?;
+            header_tree.remove(hash.as_str())?;
+            pending.extend(children.remove(&hash).unwrap_or_default());
+            removed.push(hash);
+        }
+        Ok(removed)
+    }
+
+    // Points the best header at the stored header with the most work
+    fn reset_best_header(&self) -> Result<(), BlockchainError> {
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        let mut best_hash = self.get_tip_hash();
+        let mut best_work = self.get_chain_work(best_hash.as_str())?;
+        for key in header_tree.iter().keys() {
+            let key = key?;
+            if key.as_ref() == BEST_HEADER_KEY.as_bytes() {
+                continue;
+            }
+            let hash = String::from_utf8(key.to_vec())?;
+            let work = self.get_chain_work(hash.as_str())?;
+            if work > best_work {
+                best_hash = hash;
+                best_work = work;
+            }
+        }
+        header_tree.insert(BEST_HEADER_KEY, best_hash.as_str())?;
+        Ok(())
+    }
+
+    // Disconnects active chain blocks above `height` and forgets them, so they
+    // can be downloaded again. Headers and side branches building on them are
+    // forgotten too. Returns the removed blocks, tip first.
+    pub fn rollback_to_height(&self, height: usize) -> Result<Vec<Block>, BlockchainError> {
+        let _guard = self.chain_lock.lock().unwrap();
+        let mut removed = vec![];
+        let mut tip_block = self.get_tip_block()?;
+        while tip_block.get_height() > height {
+            let parent = self.get_parent(&tip_block)?;
+            self.disconnect(&tip_block, true)?;
+            removed.push(tip_block);
+            tip_block = parent;
+        }
+        let hashes = removed.iter().map(|block| String::from(block.get_hash())).collect();
+        self.remove_branches(hashes)?;
+        self.reset_best_header()?;
+        Ok(removed)
+    }
+
+    // Applies the block to the UTXO set and moves the tip in one transaction,
+    // so a crash cannot leave the chainstate and the tip out of step
+    fn connect_block(&self, block: &Block) -> Result<(), BlockchainError> {
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let index_tree = self.db.open_tree(TX_INDEX_TREE)?;
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        (&block_tree, &utxo_tree, &undo_tree, &index_tree, &height_tree).transaction(
+            |(block_tree, utxo_tree, undo_tree, index_tree, height_tree)| {
+                UTXOSet::apply_block(utxo_tree, undo_tree, block)?;
+                TxIndex::index_block(index_tree, block)?;
+                height_tree.insert(&height_key(block.get_height()), block.get_hash())?;
+                block_tree.insert(TIP_BLOCK_HASH_KEY, block.get_hash())?;
+                Ok(())
+            },
+        )?;
+        self.set_tip_hash(block.get_hash());
+        Ok(())
+    }
+
+    fn disconnect_block(&self, block: &Block) -> Result<(), BlockchainError> {
+        self.disconnect(block, false)
+    }
+
+    // Disconnects the tip block. With `forget` the block, its chain work and
+    // its header are removed in the same transaction, and the best header
+    // falls back to the new tip.
+    fn disconnect(&self, block: &Block, forget: bool) -> Result<(), BlockchainError> {
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let utxo_tree = self.db.open_tree(UTXO_TREE)?;
+        let undo_tree = self.db.open_tree(UNDO_TREE)?;
+        let index_tree = self.db.open_tree(TX_INDEX_TREE)?;
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        let work_tree = self.db.open_tree(CHAIN_WORK_TREE)?;
+        let header_tree = self.db.open_tree(HEADERS_TREE)?;
+        let parent_hash = block.get_pre_block_hash();
+        let trees = (
+            &block_tree,
+            &utxo_tree,
+            &undo_tree,
+            &index_tree,
+            &height_tree,
+            &work_tree,
+            &header_tree,
+        );
+        trees.transaction(
+            |(block_tree, utxo_tree, undo_tree, index_tree, height_tree, work_tree, header_tree)| {
+                UTXOSet::revert_block(utxo_tree, undo_tree, block)?;
+                TxIndex::unindex_block(index_tree, block)?;
+                height_tree.remove(&height_key(block.get_height()))?;
+                block_tree.insert(TIP_BLOCK_HASH_KEY, parent_hash.as_str())?;
+                if forget {
+                    block_tree.remove(block.get_hash())?;
+                    work_tree.remove(block.get_hash())?;
+                    header_tree.remove(block.get_hash())?;
+                    header_tree.insert(BEST_HEADER_KEY, parent_hash.as_str())?;
+                }
+                Ok(())
+            },
+        )?;
+        self.set_tip_hash(parent_hash.as_str());
+        Ok(())
+    }
+
+    fn get_parent(&self, block: &Block) -> Result<Block, BlockchainError> {
+        self.get_block(block.get_pre_block_hash().as_bytes())?
+            .ok_or_else(|| BlockchainError::NotFoundError(block.get_pre_block_hash()))
+    }
+
+    fn get_tip_block(&self) -> Result<Block, BlockchainError> {
+        let tip_hash = self.get_tip_hash();
+        self.get_block(tip_hash.as_bytes())?.ok_or_else(|| {
+            BlockchainError::CorruptData(format!("tip block {} is missing", tip_hash))
+        })
+    }
+
+    // Total work of the chain ending at the block `hash`, cached per block hash
+    pub fn get_chain_work(&self, hash: &str) -> Result<BigInt, BlockchainError> {
+        let work_tree = self.db.open_tree(CHAIN_WORK_TREE)?;
+        let mut pending = vec![];
+        let mut current = String::from(hash);
+        let mut chain_work = BigInt::from(0);
+        while let Some(header) = self.get_header(current.as_str())? {
+            if let Some(work) = work_tree.get(current.as_str())? {
+                chain_work = BigInt::from_signed_bytes_be(work.as_ref());
                 break;
             }
+            let parent_hash = header.get_pre_block_hash();
+            pending.push((current, header.get_bits()));
+            current = parent_hash;
         }
-        None
+        for (hash, bits) in pending.iter().rev() {
+            chain_work += ProofOfWork::block_work(*bits);
+            work_tree.insert(hash.as_str(), chain_work.to_signed_bytes_be())?;
+        }
+        Ok(chain_work)
     }
 
-  
-    pub fn add_block(&self, block: &Block) {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        if let Some(_) = block_tree.get(block.get_hash()).unwrap() {
-            return;
-        }
-        let _: TransactionResult<(), ()> = block_tree.transaction(|tx_db| {
-            let _ = tx_db.insert(block.get_hash(), block.serialize()).unwrap();
-
-            let tip_block_bytes = tx_db
-                .get(self.get_tip_hash())
-                .unwrap()
-                .expect("The tip hash is not valid");
-            let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-            if block.get_height() > tip_block.get_height() {
-                let _ = tx_db.insert(TIP_BLOCK_HASH_KEY, block.get_hash()).unwrap();
-                self.set_tip_hash(block.get_hash());
+    // Runs every consensus check on a block before it may be stored
+    pub fn validate_block(&self, block: &Block) -> Result<(), BlockchainError> {
+        if block.get_header().get_hash() != block.get_hash() {
+            return Err(invalid_block(block.get_hash(), "hash does not match the header"));
+        }
+        self.validate_header(block.get_hash(), block.get_header())?;
+        if self.get_block(block.get_pre_block_hash().as_bytes())?.is_none() {
+            return Err(invalid_block(block.get_hash(), "previous block is not stored"));
+        }
+        Self::check_transactions(block)?;
+        // The UTXO set describes the active chain, so spends can only be
+        // checked for blocks extending the current tip.
+        if block.get_pre_block_hash() == self.get_tip_hash() {
+            self.check_spends(block)?;
+        }
+        Ok(())
+    }
+
+    // Checks everything that can be checked without the transactions
+    pub fn validate_header(
+        &self,
+        hash: &str,
+        header: &BlockHeader,
+    ) -> Result<(), BlockchainError> {
+        if !header.is_valid_pow() {
+            return Err(invalid_block(hash, "invalid proof of work"));
+        }
+        self.check_not_failed(hash, header)?;
+        let parent = self.check_parent(hash, header)?;
+        if header.get_bits() != self.get_next_bits(&parent)? {
+            return Err(invalid_block(hash, UNEXPECTED_BITS));
+        }
+        self.check_timestamp(hash, header, &parent)
+    }
+
+    // Blocks found invalid before are turned away without validating them
+    // again, and so are new blocks building on them
+    fn check_not_failed(&self, hash: &str, header: &BlockHeader) -> Result<(), BlockchainError> {
+        let failed_tree = self.db.open_tree(FAILED_TREE)?;
+        if failed_tree.contains_key(hash)? {
+            return Err(invalid_block(hash, "is known to be invalid"));
+        }
+        let parent_hash = header.get_pre_block_hash();
+        if failed_tree.contains_key(parent_hash.as_str())? {
+            failed_tree.insert(hash, vec![])?;
+            return Err(invalid_block(hash, format!("builds on invalid block {}", parent_hash)));
+        }
+        Ok(())
+    }
+
+    fn check_parent(
+        &self,
+        hash: &str,
+        header: &BlockHeader,
+    ) -> Result<BlockHeader, BlockchainError> {
+        let parent = self
+            .get_header(header.get_pre_block_hash().as_str())?
+            .ok_or_else(|| invalid_block(hash, "unknown previous block"))?;
+        if header.get_height() != parent.get_height() + 1 {
+            return Err(invalid_block(hash, "height does not follow the previous block"));
+        }
+        Ok(parent)
+    }
+
+    // Difficulty of the block following `parent`. It only changes every
+    // retarget interval, based on how long the previous window took.
+    pub fn get_next_bits(&self, parent: &BlockHeader) -> Result<u32, BlockchainError> {
+        let interval = GLOBAL_CONFIG.get_retarget_interval();
+        let height = parent.get_height() + 1;
+        if !height.is_multiple_of(interval) {
+            return Ok(parent.get_bits());
+        }
+        let first = self
+            .header_ancestors(parent, interval)?
+            .pop()
+            .unwrap_or_else(|| parent.clone());
+        let actual_timespan = parent.get_timestamp().saturating_sub(first.get_timestamp());
+        let expected_timespan =
+            GLOBAL_CONFIG.get_target_block_spacing().saturating_mul(interval as i64);
+        Ok(ProofOfWork::retarget(parent.get_bits(), actual_timespan, expected_timespan))
+    }
+
+    fn check_timestamp(
+        &self,
+        hash: &str,
+        header: &BlockHeader,
+        parent: &BlockHeader,
+    ) -> Result<(), BlockchainError> {
+        if header.get_timestamp() < self.median_time_past(parent)? {
+            return Err(invalid_block(hash, "timestamp is older than the median time past"));
+        }
+        if header.get_timestamp() > crate::current_timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(invalid_block(hash, FUTURE_TIMESTAMP));
+        }
+        Ok(())
+    }
+
+    // Median timestamp of the last MEDIAN_TIME_SPAN headers ending at `header`
+    fn median_time_past(&self, header: &BlockHeader) -> Result<i64, BlockchainError> {
+        let mut timestamps: Vec<i64> = self
+            .header_ancestors(header, MEDIAN_TIME_SPAN)?
+            .iter()
+            .map(|header| header.get_timestamp())
+            .collect();
+        timestamps.sort_unstable();
+        Ok(timestamps[timestamps.len() / 2])
+    }
+
+    // `header` followed by at most `count - 1` of its ancestors, fewer near
+    // the genesis block
+    fn header_ancestors(
+        &self,
+        header: &BlockHeader,
+        count: usize,
+    ) -> Result<Vec<BlockHeader>, BlockchainError> {
+        let mut ancestors = vec![header.clone()];
+        while ancestors.len() < count {
+            let last = ancestors.last().unwrap();
+            match self.get_header(last.get_pre_block_hash().as_str())? {
+                Some(parent) => ancestors.push(parent),
+                None => break,
             }
-            Ok(())
-        });
+        }
+        Ok(ancestors)
     }
 
+    fn check_transactions(block: &Block) -> Result<(), BlockchainError> {
+        let hash = block.get_hash();
+        let transactions = block.get_transactions();
+        if transactions.is_empty() {
+            return Err(invalid_block(hash, "no transactions"));
+        }
+        // Duplicating the last transactions of a level leaves the merkle root
+        // unchanged (CVE-2012-2459), so such a block must not be matched by it
+        let mut txids = HashSet::new();
+        if !transactions.iter().all(|tx| txids.insert(tx.get_id())) {
+            return Err(invalid_block(hash, "duplicate transactions"));
+        }
+        if block.get_merkle_root() != block.compute_merkle_root().as_slice() {
+            return Err(invalid_block(hash, "merkle root does not match the transactions"));
+        }
+        let coinbases: Vec<&Transaction> =
+            transactions.iter().filter(|tx| tx.is_coinbase()).collect();
+        if coinbases.len() != 1 {
+            return Err(invalid_block(hash, "expected exactly one coinbase transaction"));
+        }
+        if block.serialize().len() > MAX_BLOCK_SIZE {
+            return Err(invalid_block(hash, "exceeds the block size limit"));
+        }
+        for tx in transactions {
+            if !tx.has_valid_id() {
+                return Err(invalid_block(hash, "transaction id does not match its content"));
+            }
+            if tx.get_vout().iter().any(|out| out.get_value() < 0) {
+                return Err(invalid_block(hash, "negative output value"));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_spends(&self, block: &Block) -> Result<(), BlockchainError> {
+        let hash = block.get_hash();
+        // Transactions may spend outputs of those before them in the block
+        let mut view = UTXOView::new(UTXOSet::new(self.clone()));
+        let mut spent: HashSet<(Vec<u8>, usize)> = HashSet::new();
+        let mut fees: i32 = 0;
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            let reject = |reason: &str| {
+                let reason = format!("transaction {} {}", txid_hex, reason);
+                invalid_block(hash, reason)
+            };
+            if tx.get_vin().is_empty() {
+                return Err(reject("has no inputs"));
+            }
+            let mut prev_outs = vec![];
+            for vin in tx.get_vin() {
+                if !spent.insert((vin.get_txid().to_vec(), vin.get_vout())) {
+                    return Err(reject("double spends an output"));
+                }
+                let out = view
+                    .find_output(vin.get_txid(), vin.get_vout())?
+                    .ok_or_else(|| reject("spends a missing or already spent output"))?;
+                if !vin.uses_key(out.get_pub_key_hash()) {
+                    return Err(reject("spends an output it does not own"));
+                }
+                prev_outs.push(out);
+            }
+            let input_value = sum_values(&prev_outs).ok_or_else(|| reject("overflows its inputs"))?;
+            let output_value =
+                sum_values(tx.get_vout()).ok_or_else(|| reject("overflows its outputs"))?;
+            if output_value > input_value {
+                return Err(reject("spends more than its inputs"));
+            }
+            if !tx.verify(&view)? {
+                return Err(reject("has an invalid signature"));
+            }
+            fees = fees
+                .checked_add(input_value - output_value)
+                .ok_or_else(|| invalid_block(hash, "fees overflow"))?;
+            view.apply(tx);
+        }
+        // The coinbase may claim the subsidy and the fees, nothing more
+        let coinbase = block.get_transactions().iter().find(|tx| tx.is_coinbase());
+        let reward = match coinbase {
+            Some(tx) => sum_values(tx.get_vout())
+                .ok_or_else(|| invalid_block(hash, "coinbase overflows its outputs"))?,
+            None => 0,
+        };
+        let allowed = SUBSIDY
+            .checked_add(fees)
+            .ok_or_else(|| invalid_block(hash, "fees overflow"))?;
+        if reward > allowed {
+            return Err(invalid_block(hash, "coinbase pays more than the subsidy and fees"));
+        }
+        Ok(())
+    }
 
   
-    pub fn get_best_height(&self) -> usize {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let tip_block_bytes = block_tree.get(self.get_tip_hash()).unwrap().unwrap();
-        let tip_block = Block::deserialize(tip_block_bytes.as_ref());
-        tip_block.get_height()
+    pub fn get_best_height(&self) -> Result<usize, BlockchainError> {
+        Ok(self.get_tip_block()?.get_height())
     }
 
   
-    pub fn get_block(&self, block_hash: &[u8]) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
-        let result = block_tree.get(block_hash).unwrap();
+    pub fn get_block(&self, block_hash: &[u8]) -> Result<Option<Block>, BlockchainError> {
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
+        let result = block_tree.get(block_hash)?;
         
         if let Some(block_bytes) = result {
-            let block = Block::deserialize(block_bytes.as_ref());
-            Some(block)
+            let block = bincode::deserialize(block_bytes.as_ref())?;
+            Ok(Some(block))
         } else {
-            None
+            Ok(None)
         }
     }
 
-    
-    pub fn get_block_hashes(&self) -> Vec<Vec<u8>> {
-        let mut iterator = self.iterator();
-        let mut blocks = vec![];
+    // Block of the active chain at the given height
+    pub fn get_block_by_height(&self, height: usize) -> Result<Option<Block>, BlockchainError> {
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        match height_tree.get(height_key(height))? {
+            Some(block_hash) => self.get_block(block_hash.as_ref()),
+            None => Ok(None),
+        }
+    }
+
+    // Hashes of the active chain blocks from height `from` to `to` inclusive,
+    // in height order
+    pub fn get_block_hashes_range(
+        &self,
+        from: usize,
+        to: usize,
+    ) -> Result<Vec<Vec<u8>>, BlockchainError> {
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        if from > to {
+            return Ok(vec![]);
+        }
+        height_tree
+            .range(height_key(from)..=height_key(to))
+            .map(|item| Ok(item?.1.to_vec()))
+            .collect()
+    }
+
+    // Hashes of the active chain from the tip down to the genesis block, one
+    // per block for the most recent ten and exponentially spaced below them
+    pub fn get_block_locator(&self) -> Result<Vec<String>, BlockchainError> {
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        let mut locator = vec![];
+        let mut height = self.get_best_height()?;
+        let mut step = 1;
         loop {
-            let result = iterator.next();
-            if let Some(block) = result {
-                blocks.push(block.get_hash_bytes());
-            } else {
+            let hash = height_tree.get(height_key(height))?.ok_or_else(|| {
+                BlockchainError::CorruptData(format!("no block at height {}", height))
+            })?;
+            locator.push(String::from_utf8(hash.to_vec())?);
+            if height == 0 {
                 break;
             }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
         }
-        blocks
+        Ok(locator)
+    }
+
+    // Height of the first locator hash that is on the active chain, the
+    // genesis block when none of them is
+    pub fn find_fork_point(&self, locator: &[String]) -> Result<usize, BlockchainError> {
+        let height_tree = self.db.open_tree(HEIGHTS_TREE)?;
+        for hash in locator {
+            if let Some(block) = self.get_block(hash.as_bytes())? {
+                let active_hash = height_tree.get(height_key(block.get_height()))?;
+                if active_hash.is_some_and(|active| active.as_ref() == hash.as_bytes()) {
+                    return Ok(block.get_height());
+                }
+            }
+        }
+        Ok(0)
+    }
+
+    // Locator continuing a full batch after `last_hash`, which may not be
+    // stored locally yet
+    pub fn get_continuation_locator(
+        &self,
+        last_hash: String,
+    ) -> Result<Vec<String>, BlockchainError> {
+        let mut locator = vec![last_hash];
+        locator.extend(self.get_block_locator()?);
+        Ok(locator)
+    }
+
+    // Hashes of at most `max` active chain blocks following the fork point
+    // with the locator
+    pub fn get_block_hashes_after(
+        &self,
+        locator: &[String],
+        max: usize,
+    ) -> Result<Vec<Vec<u8>>, BlockchainError> {
+        let start = self.find_fork_point(locator)? + 1;
+        let end = self.get_best_height()?.min(start + max - 1);
+        self.get_block_hashes_range(start, end)
+    }
+
+    // Headers of at most `max` active chain blocks following the fork point
+    // with the locator
+    pub fn get_headers_after(
+        &self,
+        locator: &[String],
+        max: usize,
+    ) -> Result<Vec<BlockHeader>, BlockchainError> {
+        let start = self.find_fork_point(locator)? + 1;
+        let end = self.get_best_height()?.min(start + max - 1);
+        let mut headers = vec![];
+        for height in start..=end {
+            if let Some(block) = self.get_block_by_height(height)? {
+                headers.push(block.get_header().clone());
+            }
+        }
+        Ok(headers)
+    }
+
+    pub fn forward_iterator(&self) -> BlockchainForwardIterator {
+        BlockchainForwardIterator::new(self.clone())
+    }
+
+    
+    pub fn get_block_hashes(&self) -> Result<Vec<Vec<u8>>, BlockchainError> {
+        self.iterator()
+            .map(|block| Ok(block?.get_hash_bytes()))
+            .collect()
+    }
+}
+
+fn invalid_block(hash: &str, reason: impl std::fmt::Display) -> BlockchainError {
+    BlockchainError::ValidationError(format!("block {}: {}", hash, reason))
+}
+
+// Whether a header was turned away for a reason that does not prove the peer
+// dishonest, unlike a broken proof of work
+pub fn is_honest_header_failure(e: &BlockchainError) -> bool {
+    match e {
+        BlockchainError::ValidationError(reason) => {
+            reason.ends_with(FUTURE_TIMESTAMP) || reason.ends_with(UNEXPECTED_BITS)
+        }
+        _ => false,
+    }
+}
+
+/// Blocks that left and joined the active chain when a block was added
+#[derive(Default)]
+pub struct ChainUpdate {
+    disconnected: Vec<Block>,
+    connected: Vec<Block>,
+}
+
+impl ChainUpdate {
+    // Former active chain blocks, tip first
+    pub fn get_disconnected(&self) -> &[Block] {
+        self.disconnected.as_slice()
+    }
+
+    // New active chain blocks, in height order
+    pub fn get_connected(&self) -> &[Block] {
+        self.connected.as_slice()
     }
 }
 
@@ -237,19 +947,383 @@ impl BlockchainIterator {
         }
     }
 
-    pub fn next(&mut self) -> Option<Block> {
-        let block_tree = self.db.open_tree(BLOCKS_TREE).unwrap();
+    fn next_block(&mut self) -> Result<Option<Block>, BlockchainError> {
+        let block_tree = self.db.open_tree(BLOCKS_TREE)?;
         
-        let data = block_tree.get(self.current_hash.clone()).unwrap();
+        let data = block_tree.get(self.current_hash.clone())?;
         
         if let Some(block_bytes) = data {
-            let block = Block::deserialize(block_bytes.as_ref());
+            let block: Block = bincode::deserialize(block_bytes.as_ref())?;
             self.current_hash = block.get_pre_block_hash().clone();
-            Some(block)
+            Ok(Some(block))
         } else {
-            None
+            Ok(None)
+        }
+    }
+}
+
+// Walks from the tip to the genesis block and stops after an error
+impl Iterator for BlockchainIterator {
+    type Item = Result<Block, BlockchainError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.next_block();
+        if result.is_err() {
+            self.current_hash.clear();
+        }
+        result.transpose()
+    }
+}
+
+/// Walks the active chain from the genesis block up to the tip
+pub struct BlockchainForwardIterator {
+    blockchain: Blockchain,
+    next_height: usize,
+}
+
+impl BlockchainForwardIterator {
+    fn new(blockchain: Blockchain) -> BlockchainForwardIterator {
+        BlockchainForwardIterator {
+            blockchain,
+            next_height: 0,
+        }
+    }
+}
+
+impl Iterator for BlockchainForwardIterator {
+    type Item = Result<Block, BlockchainError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.blockchain.get_block_by_height(self.next_height);
+        self.next_height += 1;
+        result.transpose()
+    }
+}
+
+fn open_db() -> Result<Db, BlockchainError> {
+    let dir = current_dir().map_err(|e| BlockchainError::DatabaseError(e.to_string()))?;
+    Ok(sled::open(dir.join("data"))?)
+}
+
+// Format of a database with a chain in it, 0 when it was never recorded
+fn get_db_format(db: &Db) -> Result<u32, BlockchainError> {
+    match db.get(DB_FORMAT_KEY)? {
+        Some(bytes) => {
+            let bytes = bytes.as_ref().try_into().map_err(|_| {
+                BlockchainError::CorruptData(String::from("unreadable database format"))
+            })?;
+            Ok(u32::from_be_bytes(bytes))
         }
+        None => Ok(0),
     }
 }
 
+fn check_db_format(db: &Db) -> Result<(), BlockchainError> {
+    let format = get_db_format(db)?;
+    // An empty database is reported as having no blockchain
+    if format != DB_FORMAT && db.open_tree(BLOCKS_TREE)?.get(TIP_BLOCK_HASH_KEY)?.is_some() {
+        return Err(BlockchainError::DatabaseError(format!(
+            "database format {} is unsupported, re-run createblockchain",
+            format
+        )));
+    }
+    Ok(())
+}
+
+// Big endian keys keep the height index sorted by height
+fn height_key(height: usize) -> [u8; 8] {
+    (height as u64).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transactions::TXInput;
+    use crate::TxIndex;
+
+    fn address() -> Address {
+        Address::from_pub_key_hash(&[7; 20])
+    }
+
+    fn temporary_chain() -> Blockchain {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blockchain = Blockchain::create_in(db, &address()).unwrap();
+        UTXOSet::new(blockchain.clone()).reindex().unwrap();
+        blockchain
+    }
+
+    fn mine_on(blockchain: &Blockchain, parent: &Block, transactions: Vec<Transaction>) -> Block {
+        let bits = blockchain.get_next_bits(parent.get_header()).unwrap();
+        let mut transactions = transactions;
+        transactions.push(Transaction::new_coinbase_tx(&address(), 0));
+        let pre_block_hash = String::from(parent.get_hash());
+        Block::new_block(pre_block_hash, &transactions, parent.get_height() + 1, bits)
+    }
+
+    #[test]
+    fn failed_reorganisation_forgets_the_invalid_branch() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let tip = mine_on(&blockchain, &genesis, vec![]);
+        blockchain.add_block(&tip).unwrap();
+
+        // Spending the genesis coinbase twice is only found out once the
+        // branch overtakes the active chain
+        let coinbase_id = genesis.get_transactions()[0].get_id();
+        let vin = vec![TXInput::new(coinbase_id, 0), TXInput::new(coinbase_id, 0)];
+        let double_spend = Transaction::new_unsigned(vin, vec![TXOutput::new(1, &address())]);
+        let invalid = mine_on(&blockchain, &genesis, vec![double_spend]);
+        blockchain.add_block(&invalid).unwrap();
+        let child = mine_on(&blockchain, &invalid, vec![]);
+        let grandchild = mine_on(&blockchain, &child, vec![]);
+        let grandchild_header = grandchild.get_header().clone();
+        assert!(blockchain.add_header(child.get_header()).unwrap());
+        assert!(blockchain.add_header(&grandchild_header).unwrap());
+        assert!(blockchain.add_block(&child).is_err());
+
+        assert_eq!(blockchain.get_tip_hash(), tip.get_hash());
+        assert_eq!(blockchain.get_best_header_hash().unwrap(), tip.get_hash());
+        for hash in [invalid.get_hash(), child.get_hash(), grandchild_header.get_hash().as_str()] {
+            assert!(blockchain.get_block(hash.as_bytes()).unwrap().is_none());
+            assert!(blockchain.get_header(hash).unwrap().is_none());
+        }
+        // The branch is turned away when a peer sends it again, and so is
+        // anything new building on it
+        let Err(err) = blockchain.add_block(&invalid) else {
+            panic!("the invalid block was stored again");
+        };
+        assert!(err.to_string().contains("is known to be invalid"), "{}", err);
+        let err = blockchain.add_header(&grandchild_header).unwrap_err();
+        assert!(err.to_string().contains("is known to be invalid"), "{}", err);
+        let descendant = mine_on(&blockchain, &grandchild, vec![]);
+        let err = blockchain.add_header(descendant.get_header()).unwrap_err();
+        assert!(err.to_string().contains("builds on invalid block"), "{}", err);
+        assert!(blockchain.get_header(descendant.get_hash()).unwrap().is_none());
+    }
+
+    #[test]
+    fn the_transaction_index_follows_connected_and_disconnected_blocks() {
+        let blockchain = temporary_chain();
+        assert_eq!(TxIndex::new(blockchain.clone()).reindex().unwrap(), 1);
+        let genesis = blockchain.get_tip_block().unwrap();
+        let stale = mine_on(&blockchain, &genesis, vec![]);
+        blockchain.add_block(&stale).unwrap();
+        let stale_txid = stale.get_transactions()[0].get_id();
+        let found = blockchain.find_transaction(stale_txid).unwrap().unwrap();
+        assert_eq!(found.get_id(), stale_txid);
+
+        // A longer branch disconnects the block, which stays stored
+        let fork = mine_on(&blockchain, &genesis, vec![]);
+        blockchain.add_block(&fork).unwrap();
+        let fork_tip = mine_on(&blockchain, &fork, vec![]);
+        blockchain.add_block(&fork_tip).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), fork_tip.get_hash());
+        assert!(blockchain.get_block(stale.get_hash().as_bytes()).unwrap().is_some());
+
+        assert!(TxIndex::new(blockchain.clone()).is_enabled().unwrap());
+        assert!(blockchain.find_transaction(stale_txid).unwrap().is_none());
+        for block in [&fork, &fork_tip] {
+            let txid = block.get_transactions()[0].get_id();
+            assert!(blockchain.find_transaction(txid).unwrap().is_some());
+        }
+    }
+
+    // Mines `count` blocks on top of the tip and returns them in height order
+    fn extend(blockchain: &Blockchain, count: usize) -> Vec<Block> {
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let block = mine_on(blockchain, &blockchain.get_tip_block().unwrap(), vec![]);
+            blockchain.add_block(&block).unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn forward_hashes(blockchain: &Blockchain) -> Vec<String> {
+        blockchain
+            .forward_iterator()
+            .map(|block| String::from(block.unwrap().get_hash()))
+            .collect()
+    }
+
+    #[test]
+    fn height_lookups_stop_at_the_ends_of_the_active_chain() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let blocks = extend(&blockchain, 3);
+        let hashes: Vec<Vec<u8>> = blocks.iter().map(|block| block.get_hash_bytes()).collect();
+
+        for (height, block) in blocks.iter().enumerate() {
+            let found = blockchain.get_block_by_height(height + 1).unwrap().unwrap();
+            assert_eq!(found.get_hash(), block.get_hash());
+        }
+        assert!(blockchain.get_block_by_height(4).unwrap().is_none());
+
+        assert_eq!(blockchain.get_block_hashes_range(1, 2).unwrap(), hashes[..2]);
+        assert_eq!(blockchain.get_block_hashes_range(3, 3).unwrap(), hashes[2..]);
+        assert!(blockchain.get_block_hashes_range(2, 1).unwrap().is_empty());
+        // Ranges past the tip end at it
+        assert_eq!(blockchain.get_block_hashes_range(2, 10).unwrap(), hashes[1..]);
+        assert!(blockchain.get_block_hashes_range(5, 9).unwrap().is_empty());
+
+        let mut expected = vec![String::from(genesis.get_hash())];
+        expected.extend(blocks.iter().map(|block| String::from(block.get_hash())));
+        assert_eq!(forward_hashes(&blockchain), expected);
+    }
+
+    fn hashes_of(blocks: &[Block]) -> Vec<Vec<u8>> {
+        blocks.iter().map(|block| block.get_hash_bytes()).collect()
+    }
+
+    #[test]
+    fn locators_are_dense_near_the_tip_and_end_at_the_genesis_block() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        extend(&blockchain, 20);
+        let locator = blockchain.get_block_locator().unwrap();
+        let heights: Vec<usize> = locator
+            .iter()
+            .map(|hash| blockchain.get_block(hash.as_bytes()).unwrap().unwrap().get_height())
+            .collect();
+        // Ten in a row from the tip, then the step doubles
+        assert_eq!(heights, [20, 19, 18, 17, 16, 15, 14, 13, 12, 11, 9, 5, 0]);
+        assert_eq!(locator.last().unwrap(), genesis.get_hash());
+    }
+
+    #[test]
+    fn the_fork_point_is_the_first_locator_hash_on_the_active_chain() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let blocks = extend(&blockchain, 3);
+        // A peer on a side branch from the first block, which is stored here
+        // but not active
+        let side = mine_on(&blockchain, &blocks[0], vec![]);
+        blockchain.add_block(&side).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), blocks[2].get_hash());
+        let locator: Vec<String> = [side.get_hash(), blocks[0].get_hash(), genesis.get_hash()]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(blockchain.find_fork_point(&locator).unwrap(), 1);
+        let hashes = blockchain.get_block_hashes_after(&locator, 10).unwrap();
+        assert_eq!(hashes, hashes_of(&blocks[1..]));
+
+        // Nothing in common, the peer gets the chain from the genesis block on
+        let unknown = vec![String::from("unknown"), String::from("other")];
+        assert_eq!(blockchain.find_fork_point(&unknown).unwrap(), 0);
+        let hashes = blockchain.get_block_hashes_after(&unknown, 10).unwrap();
+        assert_eq!(hashes, hashes_of(&blocks));
+    }
+
+    // The server caps batches at MAX_INV_BLOCKS and MAX_HEADERS, smaller caps
+    // keep the chain short here
+    #[test]
+    fn full_batches_continue_after_their_last_hash() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let blocks = extend(&blockchain, 5);
+        let locator = vec![String::from(genesis.get_hash())];
+
+        let first = blockchain.get_block_hashes_after(&locator, 3).unwrap();
+        assert_eq!(first, hashes_of(&blocks[..3]));
+        let last_hash = String::from_utf8(first.last().unwrap().clone()).unwrap();
+        let locator = blockchain.get_continuation_locator(last_hash.clone()).unwrap();
+        assert_eq!(locator[0], last_hash);
+        assert_eq!(locator[1..], blockchain.get_block_locator().unwrap());
+        let second = blockchain.get_block_hashes_after(&locator, 3).unwrap();
+        assert_eq!(second, hashes_of(&blocks[3..]));
+
+        let locator = vec![String::from(genesis.get_hash())];
+        let headers = blockchain.get_headers_after(&locator, 3).unwrap();
+        let hashes: Vec<String> = headers.iter().map(|header| header.get_hash()).collect();
+        assert_eq!(hashes, blocks[..3].iter().map(|block| block.get_hash()).collect::<Vec<_>>());
+        let last_hash = headers.last().unwrap().get_hash();
+        let locator = blockchain.get_continuation_locator(last_hash).unwrap();
+        let headers = blockchain.get_headers_after(&locator, 3).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].get_hash(), blocks[4].get_hash());
+    }
 
+    #[test]
+    fn the_height_index_follows_rollbacks_and_reorganisations() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let blocks = extend(&blockchain, 3);
+        // A header ahead of the tip whose body never arrived
+        let ahead = mine_on(&blockchain, &blocks[2], vec![]);
+        assert!(blockchain.add_header(ahead.get_header()).unwrap());
+
+        assert_eq!(blockchain.rollback_to_height(1).unwrap().len(), 2);
+        // The removed blocks and the header building on them are forgotten
+        assert_eq!(blockchain.get_best_header_hash().unwrap(), blocks[0].get_hash());
+        for hash in [blocks[1].get_hash(), blocks[2].get_hash(), ahead.get_hash()] {
+            assert!(blockchain.get_header(hash).unwrap().is_none());
+        }
+        assert!(blockchain.get_block_by_height(2).unwrap().is_none());
+        assert_eq!(
+            blockchain.get_block_hashes_range(0, 5).unwrap(),
+            vec![genesis.get_hash_bytes(), blocks[0].get_hash_bytes()]
+        );
+        assert_eq!(forward_hashes(&blockchain).len(), 2);
+
+        // A longer branch from the genesis block replaces the remaining block
+        let fork = mine_on(&blockchain, &genesis, vec![]);
+        blockchain.add_block(&fork).unwrap();
+        let fork_tip = mine_on(&blockchain, &fork, vec![]);
+        blockchain.add_block(&fork_tip).unwrap();
+        assert_eq!(blockchain.get_tip_hash(), fork_tip.get_hash());
+
+        let found = blockchain.get_block_by_height(1).unwrap().unwrap();
+        assert_eq!(found.get_hash(), fork.get_hash());
+        assert_eq!(
+            blockchain.get_block_hashes_range(1, 5).unwrap(),
+            vec![fork.get_hash_bytes(), fork_tip.get_hash_bytes()]
+        );
+        let expected = [genesis.get_hash(), fork.get_hash(), fork_tip.get_hash()];
+        assert_eq!(forward_hashes(&blockchain), expected);
+    }
+
+    #[test]
+    fn duplicated_transactions_are_rejected_despite_a_matching_merkle_root() {
+        let txs: Vec<Transaction> =
+            (0..3).map(|_| Transaction::new_coinbase_tx(&address(), 0)).collect();
+        let mut mutated = txs.clone();
+        mutated.push(txs[2].clone());
+        let bits = ProofOfWork::initial_bits();
+        let block = Block::new_block(String::from("None"), &txs, 0, bits);
+        let mutated = Block::new_block(String::from("None"), &mutated, 0, bits);
+        assert_eq!(block.get_merkle_root(), mutated.get_merkle_root());
+
+        let err = Blockchain::check_transactions(&mutated).unwrap_err();
+        assert!(err.to_string().contains("duplicate transactions"), "{}", err);
+    }
+
+    #[test]
+    fn only_dishonest_header_failures_count_against_the_peer() {
+        let blockchain = temporary_chain();
+        let genesis = blockchain.get_tip_block().unwrap();
+        let bits = ProofOfWork::retarget(ProofOfWork::initial_bits(), 1, 4);
+        let coinbase = vec![Transaction::new_coinbase_tx(&address(), 0)];
+        let harder = Block::new_block(String::from(genesis.get_hash()), &coinbase, 1, bits);
+        let err = blockchain.add_header(harder.get_header()).unwrap_err();
+        assert!(is_honest_header_failure(&err), "{}", err);
+
+        let bits = ProofOfWork::initial_bits();
+        let skipped = Block::new_block(String::from(genesis.get_hash()), &coinbase, 2, bits);
+        let err = blockchain.add_header(skipped.get_header()).unwrap_err();
+        assert!(!is_honest_header_failure(&err), "{}", err);
+    }
+
+    #[test]
+    fn databases_in_another_format_start_over() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blocks_tree = db.open_tree(BLOCKS_TREE).unwrap();
+        blocks_tree.insert(TIP_BLOCK_HASH_KEY, "stale").unwrap();
+        let err = check_db_format(&db).unwrap_err();
+        assert!(err.to_string().contains("database format 0 is unsupported"), "{}", err);
+
+        let blockchain = Blockchain::create_in(db.clone(), &address()).unwrap();
+        assert_eq!(blockchain.get_best_height().unwrap(), 0);
+        assert_ne!(blockchain.get_tip_hash(), "stale");
+        check_db_format(&db).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+// Framing of packages on the wire
+use crate::server::Package;
+use crate::{BlockchainError, GLOBAL_CONFIG};
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+// Largest payload accepted from a peer, in bytes
+pub const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+const MAINNET_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+const TESTNET_MAGIC: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+const COMMAND_LEN: usize = 12;
+const CHECKSUM_LEN: usize = 4;
+// magic | command | payload length | payload checksum
+const FRAME_HEADER_LEN: usize = 4 + COMMAND_LEN + 4 + CHECKSUM_LEN;
+
+/// How packages are encoded on a connection. Binary frames are the default,
+/// JSON is kept for debugging with plain text tools.
+#[derive(Clone, Copy)]
+pub enum Codec {
+    Binary { magic: [u8; 4] },
+    Json,
+}
+
+impl Codec {
+    // The codec Config::validate checked at startup, binary mainnet frames
+    // otherwise
+    pub fn from_config() -> Codec {
+        let network = GLOBAL_CONFIG.get_network();
+        Codec::parse(network.as_str(), GLOBAL_CONFIG.get_wire_codec().as_str())
+            .unwrap_or(Codec::Binary { magic: MAINNET_MAGIC })
+    }
+
+    pub fn parse(network: &str, wire_codec: &str) -> Result<Codec, BlockchainError> {
+        let magic = match network {
+            "mainnet" => MAINNET_MAGIC,
+            "testnet" => TESTNET_MAGIC,
+            network => {
+                let reason = format!("NETWORK must be mainnet or testnet, not {}", network);
+                return Err(BlockchainError::ConfigError(reason));
+            }
+        };
+        match wire_codec {
+            "binary" => Ok(Codec::Binary { magic }),
+            "json" => Ok(Codec::Json),
+            codec => {
+                let reason = format!("WIRE_CODEC must be binary or json, not {}", codec);
+                Err(BlockchainError::ConfigError(reason))
+            }
+        }
+    }
+
+    pub fn write_package(
+        &self,
+        writer: &mut impl Write,
+        pkg: &Package,
+    ) -> Result<(), BlockchainError> {
+        let bytes = match self {
+            Codec::Binary { magic } => {
+                let payload = bincode::serialize(pkg)
+                    .map_err(|e| BlockchainError::DeserializationError(e.to_string()))?;
+                let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+                frame.extend(magic);
+                frame.extend(command_bytes(pkg.command()));
+                frame.extend((payload.len() as u32).to_le_bytes());
+                frame.extend(checksum(payload.as_slice()));
+                frame.extend(payload);
+                frame
+            }
+            Codec::Json => serde_json::to_vec(pkg)
+                .map_err(|e| BlockchainError::DeserializationError(e.to_string()))?,
+        };
+        if bytes.len() > FRAME_HEADER_LEN + MAX_MESSAGE_SIZE {
+            return Err(BlockchainError::NetworkError(format!(
+                "{} package of {} bytes exceeds the maximum message size",
+                pkg.command(),
+                bytes.len()
+            )));
+        }
+        writer.write_all(bytes.as_slice()).map_err(network_error)?;
+        writer.flush().map_err(network_error)
+    }
+
+    // Reads the next package, or None when the peer closed the connection
+    pub fn read_package(
+        &self,
+        reader: &mut impl BufRead,
+    ) -> Result<Option<Package>, BlockchainError> {
+        match self {
+            Codec::Binary { magic } => read_frame(reader, magic),
+            Codec::Json => {
+                if reader.fill_buf().map_err(network_error)?.is_empty() {
+                    return Ok(None);
+                }
+                let limited = (&mut *reader).take(MAX_MESSAGE_SIZE as u64);
+                let mut packages = serde_json::Deserializer::from_reader(limited).into_iter();
+                match packages.next() {
+                    Some(Err(e)) if e.is_io() => Err(network_error(e.into())),
+                    Some(pkg) => pkg.map(Some).map_err(|e| {
+                        BlockchainError::ProtocolError(format!("malformed payload: {}", e))
+                    }),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+}
+
+fn read_frame(
+    reader: &mut impl BufRead,
+    magic: &[u8; 4],
+) -> Result<Option<Package>, BlockchainError> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(network_error(e)),
+    }
+    if header[..4] != magic[..] {
+        return Err(BlockchainError::ProtocolError(String::from("unexpected network magic")));
+    }
+    let command = &header[4..4 + COMMAND_LEN];
+    let length_bytes = &header[4 + COMMAND_LEN..FRAME_HEADER_LEN - CHECKSUM_LEN];
+    let length = u32::from_le_bytes(length_bytes.try_into().unwrap()) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(BlockchainError::ProtocolError(format!(
+            "message of {} bytes exceeds the maximum message size",
+            length
+        )));
+    }
+    let mut payload = vec![0u8; length];
+    reader.read_exact(payload.as_mut_slice()).map_err(network_error)?;
+    if header[FRAME_HEADER_LEN - CHECKSUM_LEN..] != checksum(payload.as_slice())[..] {
+        return Err(BlockchainError::ProtocolError(String::from("payload checksum mismatch")));
+    }
+    let pkg: Package = bincode::deserialize(payload.as_slice())
+        .map_err(|e| BlockchainError::ProtocolError(format!("malformed payload: {}", e)))?;
+    if command != command_bytes(pkg.command()) {
+        return Err(BlockchainError::ProtocolError(format!(
+            "command does not match the {} payload",
+            pkg.command()
+        )));
+    }
+    Ok(Some(pkg))
+}
+
+// Command name padded with zero bytes
+fn command_bytes(command: &str) -> [u8; COMMAND_LEN] {
+    let mut bytes = [0u8; COMMAND_LEN];
+    bytes[..command.len()].copy_from_slice(command.as_bytes());
+    bytes
+}
+
+// First bytes of the double SHA-256 of the payload
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let hash = crate::sha256_digest(crate::sha256_digest(payload).as_slice());
+    hash[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+fn network_error(err: std::io::Error) -> BlockchainError {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            BlockchainError::NetworkError(String::from("timed out waiting for the peer"))
+        }
+        _ => BlockchainError::NetworkError(err.to_string()),
+    }
+}
@@ -1,15 +1,44 @@
+use crate::codec::Codec;
+use crate::BlockchainError;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::env;
+use std::str::FromStr;
 use std::sync::RwLock;
 
-pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
+pub static GLOBAL_CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 
 static DEFAULT_NODE_ADDR: &str = "127.0.0.1:2001";
+// Seconds between blocks the difficulty adjustment aims for
+static DEFAULT_TARGET_BLOCK_SPACING: &str = "10";
+// Number of blocks between two difficulty adjustments
+static DEFAULT_RETARGET_INTERVAL: &str = "20";
+// Network whose magic bytes start every message, mainnet or testnet
+static DEFAULT_NETWORK: &str = "mainnet";
+// Encoding of packages on the wire, binary or json
+static DEFAULT_WIRE_CODEC: &str = "binary";
+// Seconds a misbehaving peer stays banned
+static DEFAULT_BAN_TIME: &str = "86400";
+// Fee per 1000 bytes a transaction must pay to enter the memory pool
+static DEFAULT_MIN_RELAY_FEE: &str = "1";
+// Bytes of transactions the memory pool holds before evicting
+static DEFAULT_MAX_MEMPOOL_SIZE: &str = "5000000";
+// Seconds a transaction may wait in the memory pool, two weeks
+static DEFAULT_MEMPOOL_EXPIRY: &str = "1209600";
 
 const NODE_ADDRESS_KEY: &str = "NODE_ADDRESS";
 const MINING_ADDRESS_KEY: &str = "MINING_ADDRESS";
+const TARGET_BLOCK_SPACING_KEY: &str = "TARGET_BLOCK_SPACING";
+const RETARGET_INTERVAL_KEY: &str = "RETARGET_INTERVAL";
+const NETWORK_KEY: &str = "NETWORK";
+const WIRE_CODEC_KEY: &str = "WIRE_CODEC";
+const BAN_TIME_KEY: &str = "BAN_TIME";
+const MIN_RELAY_FEE_KEY: &str = "MIN_RELAY_FEE";
+const MAX_MEMPOOL_SIZE_KEY: &str = "MAX_MEMPOOL_SIZE";
+const MEMPOOL_EXPIRY_KEY: &str = "MEMPOOL_EXPIRY";
+const CONNECT_PEERS_KEY: &str = "CONNECT_PEERS";
+const SEED_PEERS_KEY: &str = "SEED_PEERS";
 
 
 pub struct Config {
@@ -25,6 +54,19 @@ impl Config {
         }
         let mut map = HashMap::new();
         map.insert(String::from(NODE_ADDRESS_KEY), node_addr);
+        for (key, default) in [
+            (TARGET_BLOCK_SPACING_KEY, DEFAULT_TARGET_BLOCK_SPACING),
+            (RETARGET_INTERVAL_KEY, DEFAULT_RETARGET_INTERVAL),
+            (NETWORK_KEY, DEFAULT_NETWORK),
+            (WIRE_CODEC_KEY, DEFAULT_WIRE_CODEC),
+            (BAN_TIME_KEY, DEFAULT_BAN_TIME),
+            (MIN_RELAY_FEE_KEY, DEFAULT_MIN_RELAY_FEE),
+            (MAX_MEMPOOL_SIZE_KEY, DEFAULT_MAX_MEMPOOL_SIZE),
+            (MEMPOOL_EXPIRY_KEY, DEFAULT_MEMPOOL_EXPIRY),
+        ] {
+            let value = env::var(key).unwrap_or_else(|_| String::from(default));
+            map.insert(String::from(key), value);
+        }
 
         Config {
             inner: RwLock::new(map),
@@ -32,6 +74,45 @@ impl Config {
     }
 
     
+    // Checks the values taken from the environment, so that a bad one is
+    // reported when the program starts. The getters fall back to the
+    // defaults rather than fail later.
+    pub fn validate(&self) -> Result<(), BlockchainError> {
+        let spacing: i64 = self.parse(TARGET_BLOCK_SPACING_KEY, "a number of seconds")?;
+        let interval: usize = self.parse(RETARGET_INTERVAL_KEY, "a number of blocks")?;
+        if spacing <= 0 || interval == 0 {
+            return Err(BlockchainError::ConfigError(String::from(
+                "TARGET_BLOCK_SPACING and RETARGET_INTERVAL must be above 0",
+            )));
+        }
+        let _: i64 = self.parse(BAN_TIME_KEY, "a number of seconds")?;
+        let _: u32 = self.parse(MIN_RELAY_FEE_KEY, "a fee per 1000 bytes")?;
+        let _: usize = self.parse(MAX_MEMPOOL_SIZE_KEY, "a number of bytes")?;
+        let _: i64 = self.parse(MEMPOOL_EXPIRY_KEY, "a number of seconds")?;
+        Codec::parse(self.get_network().as_str(), self.get_wire_codec().as_str())?;
+        Ok(())
+    }
+
+    fn parse<T: FromStr>(&self, key: &str, what: &str) -> Result<T, BlockchainError> {
+        let inner = self.inner.read().unwrap();
+        let value = inner.get(key).unwrap();
+        value.parse().map_err(|_| {
+            BlockchainError::ConfigError(format!("{} must be {}, not {}", key, what, value))
+        })
+    }
+
+    // A validated number, or the default when the value does not parse
+    fn get_number<T: FromStr>(&self, key: &str, default: &str) -> T {
+        let inner = self.inner.read().unwrap();
+        match inner.get(key).unwrap().parse() {
+            Ok(value) => value,
+            Err(_) => default
+                .parse()
+                .ok()
+                .unwrap_or_else(|| panic!("default {} of {} must parse", default, key)),
+        }
+    }
+
     pub fn get_node_addr(&self) -> String {
         let inner = self.inner.read().unwrap();
         inner.get(NODE_ADDRESS_KEY).unwrap().clone()
@@ -57,5 +138,79 @@ impl Config {
         let inner = self.inner.read().unwrap();
         inner.contains_key(MINING_ADDRESS_KEY)
     }
-}
 
+    // Target block spacing in milliseconds, matching block timestamps
+    pub fn get_target_block_spacing(&self) -> i64 {
+        let seconds: i64 =
+            self.get_number(TARGET_BLOCK_SPACING_KEY, DEFAULT_TARGET_BLOCK_SPACING);
+        seconds.clamp(1, i64::MAX / 1000) * 1000
+    }
+
+    pub fn get_retarget_interval(&self) -> usize {
+        let interval: usize = self.get_number(RETARGET_INTERVAL_KEY, DEFAULT_RETARGET_INTERVAL);
+        interval.max(1)
+    }
+
+    pub fn get_network(&self) -> String {
+        let inner = self.inner.read().unwrap();
+        inner.get(NETWORK_KEY).unwrap().clone()
+    }
+
+    pub fn get_wire_codec(&self) -> String {
+        let inner = self.inner.read().unwrap();
+        inner.get(WIRE_CODEC_KEY).unwrap().clone()
+    }
+
+    // Ban duration in milliseconds, matching block timestamps
+    pub fn get_ban_time(&self) -> i64 {
+        let seconds: i64 = self.get_number(BAN_TIME_KEY, DEFAULT_BAN_TIME);
+        seconds.saturating_mul(1000)
+    }
+
+    pub fn get_min_relay_fee(&self) -> i32 {
+        let fee: u32 = self.get_number(MIN_RELAY_FEE_KEY, DEFAULT_MIN_RELAY_FEE);
+        fee.min(i32::MAX as u32) as i32
+    }
+
+    pub fn get_max_mempool_size(&self) -> usize {
+        self.get_number(MAX_MEMPOOL_SIZE_KEY, DEFAULT_MAX_MEMPOOL_SIZE)
+    }
+
+    // Memory pool expiry in milliseconds, matching block timestamps
+    pub fn get_mempool_expiry(&self) -> i64 {
+        let seconds: i64 = self.get_number(MEMPOOL_EXPIRY_KEY, DEFAULT_MEMPOOL_EXPIRY);
+        seconds.saturating_mul(1000)
+    }
+
+    // Peers given with --connect, the node connects to no others
+    pub fn set_connect_peers(&self, peers: Vec<String>) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(CONNECT_PEERS_KEY), peers.join(","));
+    }
+
+    pub fn get_connect_peers(&self) -> Vec<String> {
+        self.get_list(CONNECT_PEERS_KEY)
+    }
+
+    // Peers given with --seed to learn other peers from
+    pub fn set_seed_peers(&self, peers: Vec<String>) {
+        let mut inner = self.inner.write().unwrap();
+        let _ = inner.insert(String::from(SEED_PEERS_KEY), peers.join(","));
+    }
+
+    pub fn get_seed_peers(&self) -> Vec<String> {
+        self.get_list(SEED_PEERS_KEY)
+    }
+
+    fn get_list(&self, key: &str) -> Vec<String> {
+        let inner = self.inner.read().unwrap();
+        match inner.get(key) {
+            Some(value) => value
+                .split(',')
+                .filter(|x| !x.is_empty())
+                .map(String::from)
+                .collect(),
+            None => vec![],
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::fmt;
 use std::error::Error;
+use crate::AddressError;
 
 #[derive(Debug)]
 pub enum BlockchainError {
@@ -10,6 +11,12 @@ pub enum BlockchainError {
     InvalidTransaction(String),
     NotFoundError(String),
     ValidationError(String),
+    ConfigError(String),
+    ProtocolError(String),
+    InsufficientFunds(String),
+    CorruptData(String),
+    InvalidAddress(AddressError),
+    Rejected(String),
     // Add more error types as needed
 }
 
@@ -23,6 +30,12 @@ impl fmt::Display for BlockchainError {
             BlockchainError::InvalidTransaction(msg) => write!(f, "Invalid transaction error: {}", msg),
             BlockchainError::NotFoundError(msg) => write!(f, "Not found error: {}", msg),
             BlockchainError::ValidationError(msg) => write!(f, "Validation error: {}", msg),
+            BlockchainError::ConfigError(msg) => write!(f, "Configuration error: {}", msg),
+            BlockchainError::ProtocolError(msg) => write!(f, "Protocol error: {}", msg),
+            BlockchainError::InsufficientFunds(msg) => write!(f, "Insufficient funds: {}", msg),
+            BlockchainError::CorruptData(msg) => write!(f, "Corrupt data: {}", msg),
+            BlockchainError::InvalidAddress(err) => write!(f, "Invalid address: {}", err),
+            BlockchainError::Rejected(msg) => write!(f, "Rejected: {}", msg),
         }
     }
 }
@@ -33,4 +46,32 @@ impl From<sled::Error> for BlockchainError {
     fn from(err: sled::Error) -> Self {
         BlockchainError::DatabaseError(err.to_string())
     }
+}
+
+impl From<sled::transaction::TransactionError<BlockchainError>> for BlockchainError {
+    fn from(err: sled::transaction::TransactionError<BlockchainError>) -> Self {
+        match err {
+            sled::transaction::TransactionError::Abort(err) => err,
+            sled::transaction::TransactionError::Storage(err) => err.into(),
+        }
+    }
 } 
+
+// Stored data that no longer decodes
+impl From<bincode::Error> for BlockchainError {
+    fn from(err: bincode::Error) -> Self {
+        BlockchainError::CorruptData(err.to_string())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for BlockchainError {
+    fn from(err: std::string::FromUtf8Error) -> Self {
+        BlockchainError::CorruptData(err.to_string())
+    }
+}
+
+impl From<AddressError> for BlockchainError {
+    fn from(err: AddressError) -> Self {
+        BlockchainError::InvalidAddress(err)
+    }
+}
@@ -9,27 +9,47 @@ pub use transactions::Transaction;
 
 mod utxo_set;
 pub use utxo_set::UTXOSet;
+pub use utxo_set::UTXOView;
+
+mod tx_index;
+pub use tx_index::TxIndex;
+
+mod merkle;
+pub use merkle::merkle_proof;
+pub use merkle::merkle_root;
+pub use merkle::MerkleProof;
 
 mod proof_of_work;
 use proof_of_work::ProofOfWork;
 
+mod address;
+pub use address::Address;
+pub use address::AddressError;
+pub use address::ADDRESS_CHECK_SUM_LEN;
+
 mod wallet;
-pub use wallet::convert_address;
 pub use wallet::hash_pub_key;
-pub use wallet::validate_address;
-pub use wallet::ADDRESS_CHECK_SUM_LEN;
 
 mod wallets;
 pub use wallets::Wallets;
 
 mod server;
-pub use server::send_tx;
+pub use server::submit_tx;
 pub use server::Server;
-pub use server::CENTERAL_NODE;
 
 mod node;
 
+mod ban_list;
+pub use ban_list::BanList;
+
+mod peer_store;
+pub use peer_store::PeerInfo;
+pub use peer_store::PeerStore;
+
+mod codec;
+
 mod memory_pool;
+pub use memory_pool::MemoryPool;
 
 mod config;
 pub use config::GLOBAL_CONFIG;
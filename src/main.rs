@@ -1,6 +1,6 @@
 use rust_blockchain::{
-    convert_address, hash_pub_key, send_tx, utils, validate_address, Blockchain, Server,
-    Transaction, UTXOSet, Wallets, ADDRESS_CHECK_SUM_LEN, CENTERAL_NODE, GLOBAL_CONFIG,
+    hash_pub_key, submit_tx, Address, BanList, Blockchain, BlockchainError, MemoryPool, PeerStore,
+    Server, Transaction, TxIndex, UTXOSet, UTXOView, Wallets, GLOBAL_CONFIG,
 };
 use data_encoding::HEXLOWER;
 use log::LevelFilter;
@@ -40,46 +40,92 @@ enum Command {
         to: String,
         #[structopt(long = "amount", help = "Amount to send")]
         amount: i32,
+        #[structopt(long = "fee", default_value = "1", help = "Fee paid to the miner")]
+        fee: i32,
         #[structopt(long = "mine", help = "Mine immediately on the same node")]
         mine: bool,
+        #[structopt(
+            long = "connect",
+            required_unless = "mine",
+            help = "Node to send the transaction to"
+        )]
+        connect: Option<String>,
+        #[structopt(long = "rbf", help = "Allow replacing the transaction with bumpfee")]
+        rbf: bool,
+    },
+    #[structopt(name = "bumpfee", about = "Replace an unconfirmed transaction by a higher fee")]
+    BumpFee {
+        #[structopt(name = "txid", help = "The transaction sent with --rbf")]
+        txid: String,
+        #[structopt(long = "fee", help = "The new fee, by default the lowest one accepted")]
+        fee: Option<i32>,
+        #[structopt(long = "connect", help = "Node to send the replacement to")]
+        connect: String,
     },
     #[structopt(name = "printchain", about = "Print blockchain all block")]
     Printchain,
     #[structopt(name = "reindexutxo", about = "rebuild UTXO index set")]
     Reindexutxo,
+    #[structopt(name = "reindex", about = "Rebuild the UTXO set and optional indexes")]
+    Reindex {
+        #[structopt(long = "txindex", help = "Also build and maintain the transaction index")]
+        txindex: bool,
+    },
+    #[structopt(name = "rollback", about = "Disconnect blocks above the given height")]
+    Rollback {
+        #[structopt(long = "to-height", help = "Height of the new tip block")]
+        to_height: usize,
+    },
     #[structopt(name = "startnode", about = "Start a node with mining rewards going to a miner")]
     StartNode {
         #[structopt(long = "miner", help = "The miner address")]
         miner: Option<String>,
+        #[structopt(long = "connect", help = "Connect only to this peer, may be repeated")]
+        connect: Vec<String>,
+        #[structopt(long = "seed", help = "Peer to learn other peers from, may be repeated")]
+        seed: Vec<String>,
+    },
+    #[structopt(name = "listpeers", about = "Print the known peers")]
+    ListPeers,
+    #[structopt(name = "listbanned", about = "Print the banned peers")]
+    ListBanned,
+    #[structopt(name = "clearbanned", about = "Lift the ban of a peer or of all peers")]
+    ClearBanned {
+        #[structopt(long = "addr", help = "The peer IP to unban, all peers when omitted")]
+        addr: Option<String>,
     },
 }
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let opt = Opt::from_args();
-    match opt.command {
+    if let Err(e) = run(opt.command) {
+        eprintln!("ERROR: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), BlockchainError> {
+    GLOBAL_CONFIG.validate()?;
+    match command {
         Command::Createblockchain { address } => {
-            let blockchain = Blockchain::create_blockchain(address.as_str());
+            let address: Address = address.parse()?;
+            let blockchain = Blockchain::create_blockchain(&address)?;
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
+            utxo_set.reindex()?;
             println!("Done!");
         }
         Command::Createwallet => {
-            let mut wallet = Wallets::new();
-            let address = wallet.create_wallet();
+            let mut wallet = Wallets::new()?;
+            let address = wallet.create_wallet()?;
             println!("Your new address: {}", address)
         }
         Command::GetBalance { address } => {
-            let address_valid = validate_address(address.as_str());
-            if address_valid == false {
-                panic!("ERROR: Address is not valid")
-            }
-            let payload = utils::base58_decode(address.as_str());
-            let pub_key_hash = &payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN];
+            let address: Address = address.parse()?;
 
-            let blockchain = Blockchain::new_blockchain();
+            let blockchain = Blockchain::new_blockchain()?;
             let utxo_set = UTXOSet::new(blockchain);
-            let utxos = utxo_set.find_utxo(pub_key_hash);
+            let utxos = utxo_set.find_utxo(address.get_pub_key_hash())?;
             let mut balance = 0;
             for utxo in utxos {
                 balance += utxo.get_value();
@@ -87,7 +133,7 @@ fn main() {
             println!("Balance of {}: {}", address, balance);
         }
         Command::ListAddresses => {
-            let wallets = Wallets::new();
+            let wallets = Wallets::new()?;
             for address in wallets.get_addresses() {
                 println!("{}", address)
             }
@@ -96,84 +142,169 @@ fn main() {
             from,
             to,
             amount,
+            fee,
             mine,
+            connect,
+            rbf,
         } => {
-            if !validate_address(from.as_str()) {
-                panic!("ERROR: Sender address is not valid")
-            }
-            if !validate_address(to.as_str()) {
-                panic!("ERROR: Recipient address is not valid")
-            }
-            let blockchain = Blockchain::new_blockchain();
-            let utxo_set = UTXOSet::new(blockchain.clone());
-        
-            let transaction =
-                Transaction::new_utxo_transaction(from.as_str(), to.as_str(), amount, &utxo_set);
+            let from: Address = from.parse()?;
+            let to: Address = to.parse()?;
+            let blockchain = Blockchain::new_blockchain()?;
 
             if mine {
-                let coinbase_tx = Transaction::new_coinbase_tx(from.as_str());
-                let block = blockchain.mine_block(&vec![transaction, coinbase_tx]);
-                utxo_set.update(&block);
+                let view = UTXOView::new(UTXOSet::new(blockchain.clone()));
+                let transaction =
+                    Transaction::new_utxo_transaction(&from, &to, amount, fee, rbf, &view)?;
+                println!("Transaction id: {}", HEXLOWER.encode(transaction.get_id()));
+                let coinbase_tx = Transaction::new_coinbase_tx(&from, fee);
+                blockchain.mine_block(&[transaction, coinbase_tx])?;
             } else {
-                send_tx(CENTERAL_NODE, &transaction);
+                // Transactions sent before stay in the local pool until they
+                // expire or are no longer valid on the chain, so their outputs
+                // can be spent and their inputs are not reused
+                let memory_pool = MemoryPool::new();
+                memory_pool.load(&blockchain)?;
+                let view = memory_pool.view(&blockchain);
+                let transaction =
+                    Transaction::new_utxo_transaction(&from, &to, amount, fee, rbf, &view)?;
+                println!("Transaction id: {}", HEXLOWER.encode(transaction.get_id()));
+                // The local pool only keeps what the node accepted as well,
+                // it is saved once the node took the transaction
+                memory_pool.accept(&blockchain, transaction.clone())?;
+                if let Some(addr) = connect {
+                    submit_tx(addr.as_str(), &transaction)?;
+                }
+                memory_pool.save(&blockchain)?;
             }
             println!("Success!")
         }
+        Command::BumpFee { txid, fee, connect } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let memory_pool = MemoryPool::new();
+            memory_pool.load(&blockchain)?;
+            let original = memory_pool.get(txid.as_str()).ok_or_else(|| {
+                BlockchainError::NotFoundError(format!("transaction {} in the memory pool", txid))
+            })?;
+            let fee = match fee {
+                Some(fee) => fee,
+                None => memory_pool.get_replacement_fee(txid.as_str()).ok_or_else(|| {
+                    let reason = format!("{} cannot be replaced by a higher fee", txid);
+                    BlockchainError::InvalidTransaction(reason)
+                })?,
+            };
+            let view = memory_pool.view_without(&blockchain, txid.as_str());
+            let replacement = Transaction::new_replacement(&original, fee, &view)?;
+            println!("Transaction id: {}", HEXLOWER.encode(replacement.get_id()));
+            memory_pool.accept(&blockchain, replacement.clone())?;
+            submit_tx(connect.as_str(), &replacement)?;
+            memory_pool.save(&blockchain)?;
+            println!("Success!")
+        }
         Command::Printchain => {
-            let mut block_iterator = Blockchain::new_blockchain().iterator();
-            loop {
-                let result = block_iterator.next();
-                if let Some(block) = result {
-                    println!("Pre block hash: {}", block.get_pre_block_hash());
-                    println!("Cur block hash: {}", block.get_hash());
-                    println!("Cur block Timestamp: {}", block.get_timestamp());
-                    for tx in block.get_transactions() {
-                        let cur_txid_hex = HEXLOWER.encode(tx.get_id());
-                        println!("- Transaction txid_hex: {}", cur_txid_hex);
+            for block in Blockchain::new_blockchain()?.iterator() {
+                let block = block?;
+                println!("Pre block hash: {}", block.get_pre_block_hash());
+                println!("Cur block hash: {}", block.get_hash());
+                println!("Cur block Timestamp: {}", block.get_timestamp());
+                println!("Merkle root: {}", HEXLOWER.encode(block.get_merkle_root()));
+                println!("PoW valid: {}", block.is_valid_pow());
+                for tx in block.get_transactions() {
+                    let cur_txid_hex = HEXLOWER.encode(tx.get_id());
+                    println!("- Transaction txid_hex: {}", cur_txid_hex);
 
-                        if tx.is_coinbase() == false {
-                            for input in tx.get_vin() {
-                                let txid_hex = HEXLOWER.encode(input.get_txid());
-                                let pub_key_hash = hash_pub_key(input.get_pub_key());
-                                let address = convert_address(pub_key_hash.as_slice());
-                                println!(
-                                    "-- Input txid = {}, vout = {}, from = {}",
-                                    txid_hex,
-                                    input.get_vout(),
-                                    address,
-                                )
-                            }
-                        }
-                        for output in tx.get_vout() {
-                            let pub_key_hash = output.get_pub_key_hash();
-                            let address = convert_address(pub_key_hash);
-                            println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                    if !tx.is_coinbase() {
+                        for input in tx.get_vin() {
+                            let txid_hex = HEXLOWER.encode(input.get_txid());
+                            let pub_key_hash = hash_pub_key(input.get_pub_key());
+                            let address = Address::from_pub_key_hash(pub_key_hash.as_slice());
+                            println!(
+                                "-- Input txid = {}, vout = {}, from = {}",
+                                txid_hex,
+                                input.get_vout(),
+                                address,
+                            )
                         }
                     }
-                    println!()
-                } else {
-                    break;
+                    for output in tx.get_vout() {
+                        let pub_key_hash = output.get_pub_key_hash();
+                        let address = Address::from_pub_key_hash(pub_key_hash);
+                        println!("-- Output value = {}, to = {}", output.get_value(), address,)
+                    }
                 }
+                println!()
             }
         }
         Command::Reindexutxo => {
-            let blockchain = Blockchain::new_blockchain();
+            let blockchain = Blockchain::new_blockchain()?;
             let utxo_set = UTXOSet::new(blockchain);
-            utxo_set.reindex();
-            let count = utxo_set.count_transactions();
+            utxo_set.reindex()?;
+            let count = utxo_set.count_transactions()?;
             println!("Done! There are {} transactions in the UTXO set.", count);
         }
-        Command::StartNode { miner } => {
+        Command::Reindex { txindex } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let utxo_set = UTXOSet::new(blockchain.clone());
+            utxo_set.reindex()?;
+            println!("There are {} transactions in the UTXO set.", utxo_set.count_transactions()?);
+            if txindex {
+                let count = TxIndex::new(blockchain).reindex()?;
+                println!("There are {} transactions in the transaction index.", count);
+            }
+            println!("Done!");
+        }
+        Command::Rollback { to_height } => {
+            let blockchain = Blockchain::new_blockchain()?;
+            let removed = blockchain.rollback_to_height(to_height)?;
+            for block in &removed {
+                println!("Disconnected block {}", block.get_hash());
+            }
+            println!("Done! The tip is now at height {}.", blockchain.get_best_height()?);
+        }
+        Command::StartNode {
+            miner,
+            connect,
+            seed,
+        } => {
             if let Some(addr) = miner {
-                if validate_address(addr.as_str()) == false {
-                    panic!("Wrong miner address!")
-                }
+                addr.parse::<Address>()?;
                 println!("Mining is on. Address to receive rewards: {}", addr);
                 GLOBAL_CONFIG.set_mining_addr(addr);
             }
-            let blockchain = Blockchain::new_blockchain();
+            GLOBAL_CONFIG.set_connect_peers(connect);
+            GLOBAL_CONFIG.set_seed_peers(seed);
+            let blockchain = Blockchain::new_blockchain()?;
             let sockert_addr = GLOBAL_CONFIG.get_node_addr();
-            Server::new(blockchain).run(sockert_addr.as_str());
+            Server::new(blockchain).run(sockert_addr.as_str())?;
+        }
+        Command::ListPeers => {
+            let peer_store = PeerStore::new(Blockchain::new_blockchain()?);
+            for (addr, info) in peer_store.get_peers()? {
+                println!(
+                    "{} last seen: {}, successes: {}, failures: {}",
+                    addr,
+                    info.get_last_seen(),
+                    info.get_successes(),
+                    info.get_failures()
+                );
+            }
+        }
+        Command::ListBanned => {
+            let ban_list = BanList::new(Blockchain::new_blockchain()?);
+            for (addr, until) in ban_list.get_bans()? {
+                println!("{} banned until {}", addr, until);
+            }
+        }
+        Command::ClearBanned { addr } => {
+            let ban_list = BanList::new(Blockchain::new_blockchain()?);
+            let ip = match addr {
+                Some(addr) => Some(addr.parse().map_err(|_| {
+                    BlockchainError::NetworkError(format!("invalid peer IP {}", addr))
+                })?),
+                None => None,
+            };
+            let count = ban_list.clear(ip)?;
+            println!("Cleared {} bans.", count);
         }
     }
+    Ok(())
 }
@@ -1,95 +1,817 @@
-use crate::Transaction;
+use crate::transactions::{sum_values, TXOutput};
+use crate::utxo_set::OutPoint;
+use crate::{Block, Blockchain, BlockchainError, Transaction, UTXOSet, UTXOView, GLOBAL_CONFIG};
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
-/// ( K -> txid_hex, V => Transaction )
+const MEMPOOL_TREE: &str = "mempool";
+
+// An unconfirmed transaction with the fee it pays to the miner
+#[derive(Clone)]
+struct PoolEntry {
+    tx: Transaction,
+    fee: i32,
+    size: usize,
+    time: i64, // when it entered the pool, in milliseconds
+}
+
+// What is kept on disk, the fee is computed again when loading
+#[derive(Serialize, Deserialize)]
+struct SavedEntry {
+    tx: Transaction,
+    time: i64,
+}
+
+impl PoolEntry {
+    // Compares fee per byte without rounding
+    fn cmp_fee_rate(&self, other: &PoolEntry) -> Ordering {
+        let rate = self.fee as i64 * other.size as i64;
+        let other_rate = other.fee as i64 * self.size as i64;
+        rate.cmp(&other_rate)
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    entries: HashMap<String, PoolEntry>, // ( K -> txid_hex, V -> entry )
+    spends: HashMap<OutPoint, String>,   // ( K -> outpoint, V -> txid_hex spending it )
+    size: usize,                         // bytes of all entries
+    changed: bool,                       // since it was last saved
+}
+
+impl PoolState {
+    fn insert(&mut self, txid_hex: String, entry: PoolEntry) {
+        for vin in entry.tx.get_vin() {
+            let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+            self.spends.insert(outpoint, txid_hex.clone());
+        }
+        self.size += entry.size;
+        self.changed = true;
+        self.entries.insert(txid_hex, entry);
+    }
+
+    fn remove(&mut self, txid_hex: &str) -> Option<PoolEntry> {
+        let entry = self.entries.remove(txid_hex)?;
+        for vin in entry.tx.get_vin() {
+            self.spends.remove(&(vin.get_txid().to_vec(), vin.get_vout()));
+        }
+        self.size -= entry.size;
+        self.changed = true;
+        Some(entry)
+    }
+
+    // Output of a pool transaction
+    fn find_output(&self, txid: &[u8], vout: usize) -> Option<TXOutput> {
+        let entry = self.entries.get(HEXLOWER.encode(txid).as_str())?;
+        entry.tx.get_vout().get(vout).cloned()
+    }
+
+    // The transaction and every pool transaction spending its outputs,
+    // directly or through others
+    fn with_descendants(&self, txid_hex: &str) -> Vec<String> {
+        let mut found = vec![String::from(txid_hex)];
+        let mut idx = 0;
+        while idx < found.len() {
+            if let Some(entry) = self.entries.get(found[idx].as_str()) {
+                for vout in 0..entry.tx.get_vout().len() {
+                    let outpoint = (entry.tx.get_id_bytes(), vout);
+                    if let Some(child) = self.spends.get(&outpoint)
+                        && !found.contains(child)
+                    {
+                        found.push(child.clone());
+                    }
+                }
+            }
+            idx += 1;
+        }
+        found
+    }
+
+    // The pool transactions the transaction spends outputs of, directly or
+    // through others, parents before their children and the transaction last
+    fn with_ancestors(&self, txid_hex: &str) -> Vec<String> {
+        let mut found = vec![];
+        self.visit_ancestors(txid_hex, &mut found);
+        found
+    }
+
+    fn visit_ancestors(&self, txid_hex: &str, found: &mut Vec<String>) {
+        if found.iter().any(|visited| visited == txid_hex) {
+            return;
+        }
+        let Some(entry) = self.entries.get(txid_hex) else {
+            return;
+        };
+        for vin in entry.tx.get_vin() {
+            let parent = HEXLOWER.encode(vin.get_txid());
+            if self.entries.contains_key(parent.as_str()) {
+                self.visit_ancestors(parent.as_str(), found);
+            }
+        }
+        found.push(String::from(txid_hex));
+    }
+
+    fn remove_with_descendants(&mut self, txid_hex: &str) -> Vec<String> {
+        let removed = self.with_descendants(txid_hex);
+        for txid_hex in &removed {
+            self.remove(txid_hex);
+        }
+        removed
+    }
+
+    // Transactions to evict, lowest fee rate first, so that `entry` fits in
+    // `max_size` bytes. None when `entry` does not pay more per byte than
+    // what it would replace or would lose one of its parents.
+    fn eviction_set(&self, entry: &PoolEntry, max_size: usize) -> Option<Vec<String>> {
+        let mut candidates: Vec<(&String, &PoolEntry)> = self.entries.iter().collect();
+        candidates.sort_by(|(_, a), (_, b)| a.cmp_fee_rate(b));
+        let parents: HashSet<String> = entry
+            .tx
+            .get_vin()
+            .iter()
+            .map(|vin| HEXLOWER.encode(vin.get_txid()))
+            .collect();
+
+        let mut evicted: Vec<String> = vec![];
+        let mut size = self.size;
+        for (txid_hex, candidate) in candidates {
+            if size + entry.size <= max_size {
+                break;
+            }
+            if evicted.contains(txid_hex) {
+                continue;
+            }
+            if candidate.cmp_fee_rate(entry) != Ordering::Less {
+                return None;
+            }
+            for txid_hex in self.with_descendants(txid_hex) {
+                if parents.contains(&txid_hex) {
+                    return None;
+                }
+                if !evicted.contains(&txid_hex) {
+                    size -= self.entries[&txid_hex].size;
+                    evicted.push(txid_hex);
+                }
+            }
+        }
+        if size + entry.size > max_size {
+            return None;
+        }
+        Some(evicted)
+    }
+}
+
+/// Unconfirmed transactions waiting to be mined, indexed by the outputs they spend
+#[derive(Default)]
 pub struct MemoryPool {
-    inner: RwLock<HashMap<String, Transaction>>,
+    inner: RwLock<PoolState>,
 }
 
 impl MemoryPool {
     pub fn new() -> MemoryPool {
         MemoryPool {
-            inner: RwLock::new(HashMap::new()),
+            inner: RwLock::new(PoolState::default()),
         }
     }
 
     pub fn contains(&self, txid_hex: &str) -> bool {
-        self.inner.read().unwrap().contains_key(txid_hex)
+        self.inner.read().unwrap().entries.contains_key(txid_hex)
     }
 
-    pub fn add(&self, tx: Transaction) {
+    // Admits a transaction whose inputs are in the UTXO set or are outputs of
+    // pool transactions. Returns false when it is already in the pool.
+    // Invalid transactions fail with InvalidTransaction, valid ones the
+    // policy turns away with Rejected. A transaction spending the same
+    // outputs as replaceable pool transactions replaces them and their
+    // descendants when it pays a higher fee and fee rate.
+    pub fn accept(
+        &self,
+        blockchain: &Blockchain,
+        tx: Transaction,
+    ) -> Result<bool, BlockchainError> {
+        self.admit(blockchain, tx, crate::current_timestamp())
+    }
+
+    fn admit(
+        &self,
+        blockchain: &Blockchain,
+        tx: Transaction,
+        time: i64,
+    ) -> Result<bool, BlockchainError> {
         let txid_hex = HEXLOWER.encode(tx.get_id());
-        self.inner.write().unwrap().insert(txid_hex, tx);
+        let invalid = |reason: &str| {
+            BlockchainError::InvalidTransaction(format!("{} {}", txid_hex, reason))
+        };
+        let rejected =
+            |reason: String| BlockchainError::Rejected(format!("{} {}", txid_hex, reason));
+        if tx.is_coinbase() {
+            return Err(invalid("is a coinbase outside of a block"));
+        }
+        if !tx.has_valid_id() {
+            return Err(invalid("has an id that does not match its content"));
+        }
+        if tx.get_vout().iter().any(|out| out.get_value() < 0) {
+            return Err(invalid("has a negative output value"));
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        if inner.entries.contains_key(txid_hex.as_str()) {
+            return Ok(false);
+        }
+        let utxo_set = UTXOSet::new(blockchain.clone());
+        let mut outpoints = HashSet::new();
+        let mut conflicts: Vec<String> = vec![];
+        for vin in tx.get_vin() {
+            let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+            if !outpoints.insert(outpoint.clone()) {
+                return Err(invalid("spends an output twice"));
+            }
+            if let Some(spender) = inner.spends.get(&outpoint) {
+                if !inner.entries[spender].tx.is_replaceable() {
+                    return Err(rejected(format!(
+                        "conflicts with {} in the memory pool, which is not replaceable",
+                        spender
+                    )));
+                }
+                if !conflicts.contains(spender) {
+                    conflicts.push(spender.clone());
+                }
+            }
+        }
+        let mut replaced: Vec<String> = vec![];
+        for conflict in &conflicts {
+            for txid_hex in inner.with_descendants(conflict) {
+                if !replaced.contains(&txid_hex) {
+                    replaced.push(txid_hex);
+                }
+            }
+        }
+
+        let mut prev_outs = vec![];
+        for vin in tx.get_vin() {
+            if replaced.contains(&HEXLOWER.encode(vin.get_txid())) {
+                return Err(rejected(String::from(
+                    "spends an output of a transaction it replaces",
+                )));
+            }
+            let prev_out = match utxo_set.find_output(vin.get_txid(), vin.get_vout())? {
+                Some(prev_out) => prev_out,
+                None => inner
+                    .find_output(vin.get_txid(), vin.get_vout())
+                    .ok_or_else(|| rejected(String::from("spends unknown outputs")))?,
+            };
+            if !vin.uses_key(prev_out.get_pub_key_hash()) {
+                return Err(invalid("spends an output it does not own"));
+            }
+            prev_outs.push(prev_out);
+        }
+        if prev_outs.is_empty() {
+            return Err(invalid("has no inputs"));
+        }
+        let input_value = sum_values(&prev_outs).ok_or_else(|| invalid("overflows its inputs"))?;
+        let output_value =
+            sum_values(tx.get_vout()).ok_or_else(|| invalid("overflows its outputs"))?;
+        let fee = input_value - output_value;
+        if fee < 0 {
+            return Err(invalid("spends more than its inputs"));
+        }
+        if !tx.verify_signatures(prev_outs.as_slice()) {
+            return Err(invalid("has an invalid signature"));
+        }
+
+        let size = tx.get_size();
+        let min_fee = min_relay_fee(size);
+        if fee < min_fee {
+            return Err(rejected(format!(
+                "pays a fee of {} below the minimum relay fee of {}",
+                fee, min_fee
+            )));
+        }
+        let entry = PoolEntry {
+            tx,
+            fee,
+            size,
+            time,
+        };
+        let replaced_fee: i64 =
+            replaced.iter().map(|txid_hex| inner.entries[txid_hex].fee as i64).sum();
+        if !replaced.is_empty() && fee as i64 <= replaced_fee {
+            return Err(rejected(format!(
+                "pays a fee of {} not above the fee of {} of the transactions it replaces",
+                fee, replaced_fee
+            )));
+        }
+        for conflict in &conflicts {
+            if entry.cmp_fee_rate(&inner.entries[conflict]) != Ordering::Greater {
+                return Err(rejected(format!(
+                    "pays a fee rate not above the one of {} it replaces",
+                    conflict
+                )));
+            }
+        }
+
+        // The replaced transactions make room first, they are restored when
+        // the replacement still does not fit
+        let mut removed = vec![];
+        for txid_hex in &replaced {
+            if let Some(replaced_entry) = inner.remove(txid_hex) {
+                removed.push((txid_hex.clone(), replaced_entry));
+            }
+        }
+        let Some(evicted) = inner.eviction_set(&entry, GLOBAL_CONFIG.get_max_mempool_size())
+        else {
+            for (txid_hex, replaced_entry) in removed {
+                inner.insert(txid_hex, replaced_entry);
+            }
+            return Err(rejected(String::from("does not fit in the full memory pool")));
+        };
+        for replaced_txid_hex in &replaced {
+            info!(
+                "Replaced transaction {} in the memory pool with {}",
+                replaced_txid_hex, txid_hex
+            );
+        }
+        for txid_hex in &evicted {
+            inner.remove(txid_hex);
+            info!("Evicted transaction {} from the memory pool", txid_hex);
+        }
+        inner.insert(txid_hex, entry);
+        Ok(true)
     }
 
     pub fn get(&self, txid_hex: &str) -> Option<Transaction> {
-        if let Some(tx) = self.inner.read().unwrap().get(txid_hex) {
-            return Some(tx.clone());
+        if let Some(entry) = self.inner.read().unwrap().entries.get(txid_hex) {
+            return Some(entry.tx.clone());
         }
         None
     }
 
-    pub fn remove(&self, txid_hex: &str) {
+    // Drops the transactions of a connected block and the pool transactions
+    // that conflict with them, along with their descendants
+    pub fn remove_confirmed(&self, block: &Block) {
         let mut inner = self.inner.write().unwrap();
-        inner.remove(txid_hex);
+        for tx in block.get_transactions() {
+            inner.remove(HEXLOWER.encode(tx.get_id()).as_str());
+        }
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            for vin in tx.get_vin() {
+                let outpoint = (vin.get_txid().to_vec(), vin.get_vout());
+                if let Some(spender) = inner.spends.get(&outpoint).cloned() {
+                    for txid_hex in inner.remove_with_descendants(spender.as_str()) {
+                        info!("Dropped transaction {} double spent by a block", txid_hex);
+                    }
+                }
+            }
+        }
     }
 
-    pub fn get_all(&self) -> Vec<Transaction> {
+    // Picks the transactions whose package, the transaction with its
+    // unconfirmed ancestors, pays the highest fee per byte and fits in
+    // `max_size` bytes. A child paying for its parents thus brings them in.
+    // Returns them, parents before their children, with the sum of their
+    // fees.
+    pub fn select_for_block(&self, max_size: usize) -> (Vec<Transaction>, i32) {
         let inner = self.inner.read().unwrap();
+        // ( package, fee, size, time )
+        let mut packages: Vec<(Vec<String>, i64, i64, i64)> = inner
+            .entries
+            .iter()
+            .map(|(txid_hex, entry)| {
+                let package = inner.with_ancestors(txid_hex);
+                let members = package.iter().map(|txid_hex| &inner.entries[txid_hex]);
+                let fee = members.clone().map(|member| member.fee as i64).sum();
+                let size = members.map(|member| member.size as i64).sum();
+                (package, fee, size, entry.time)
+            })
+            .collect();
+        // Highest fee rate first without rounding, oldest first on a tie
+        packages.sort_by(|(_, a_fee, a_size, a_time), (_, b_fee, b_size, b_time)| {
+            (b_fee * a_size).cmp(&(a_fee * b_size)).then(a_time.cmp(b_time))
+        });
+
+        let mut selected: HashSet<String> = HashSet::new();
         let mut txs = vec![];
-        for (_, v) in inner.iter() {
-            txs.push(v.clone());
+        let mut fees = 0;
+        let mut size = 0;
+        for (package, ..) in packages {
+            let missing: Vec<&PoolEntry> = package
+                .iter()
+                .filter(|txid_hex| !selected.contains(*txid_hex))
+                .map(|txid_hex| &inner.entries[txid_hex])
+                .collect();
+            let missing_size: usize = missing.iter().map(|entry| entry.size).sum();
+            if missing.is_empty() || size + missing_size > max_size {
+                continue;
+            }
+            for entry in missing {
+                selected.insert(HEXLOWER.encode(entry.tx.get_id()));
+                size += entry.size;
+                fees += entry.fee;
+                txs.push(entry.tx.clone());
+            }
         }
-        txs
+        (txs, fees)
+    }
+
+    // The UTXO set as it will be once the pool transactions are mined
+    pub fn view(&self, blockchain: &Blockchain) -> UTXOView {
+        self.view_excluding(blockchain, &[])
+    }
+
+    // The view to build a replacement of the transaction from, where the
+    // outputs it spends are unspent again
+    pub fn view_without(&self, blockchain: &Blockchain, txid_hex: &str) -> UTXOView {
+        let replaced = self.inner.read().unwrap().with_descendants(txid_hex);
+        self.view_excluding(blockchain, replaced.as_slice())
+    }
+
+    fn view_excluding(&self, blockchain: &Blockchain, excluded: &[String]) -> UTXOView {
+        let mut view = UTXOView::new(UTXOSet::new(blockchain.clone()));
+        for (txid_hex, entry) in self.inner.read().unwrap().entries.iter() {
+            if !excluded.contains(txid_hex) {
+                view.apply(&entry.tx);
+            }
+        }
+        view
+    }
+
+    // Lowest fee a replacement of the transaction pays: more than it and its
+    // descendants together, by its own relay fee. None when the transaction
+    // is not in the pool or no fee is high enough.
+    pub fn get_replacement_fee(&self, txid_hex: &str) -> Option<i32> {
+        let inner = self.inner.read().unwrap();
+        let entry = inner.entries.get(txid_hex)?;
+        let replaced = inner.with_descendants(txid_hex);
+        let replaced_fee: i64 =
+            replaced.iter().map(|txid_hex| inner.entries[txid_hex].fee as i64).sum();
+        i32::try_from(replaced_fee + min_relay_fee(entry.size).max(1) as i64).ok()
     }
 
     pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+        self.inner.read().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.read().unwrap().entries.is_empty()
+    }
+
+    // Drops the transactions that have waited longer than the configured
+    // expiry, along with their descendants. Returns their ids.
+    pub fn expire(&self) -> Vec<String> {
+        let oldest = crate::current_timestamp().saturating_sub(GLOBAL_CONFIG.get_mempool_expiry());
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<String> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time < oldest)
+            .map(|(txid_hex, _)| txid_hex.clone())
+            .collect();
+        let mut removed = vec![];
+        for txid_hex in expired {
+            if inner.entries.contains_key(txid_hex.as_str()) {
+                removed.extend(inner.remove_with_descendants(txid_hex.as_str()));
+            }
+        }
+        removed
+    }
+
+    // Writes the pool to the database when it changed since the last save.
+    // Returns the number of saved transactions.
+    pub fn save(&self, blockchain: &Blockchain) -> Result<Option<usize>, BlockchainError> {
+        let tree = blockchain.get_db().open_tree(MEMPOOL_TREE)?;
+        let mut inner = self.inner.write().unwrap();
+        if !inner.changed {
+            return Ok(None);
+        }
+        let mut batch = sled::Batch::default();
+        for key in tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for (txid_hex, entry) in inner.entries.iter() {
+            let saved = SavedEntry {
+                tx: entry.tx.clone(),
+                time: entry.time,
+            };
+            batch.insert(txid_hex.as_str(), bincode::serialize(&saved)?);
+        }
+        tree.apply_batch(batch)?;
+        tree.flush()?;
+        inner.changed = false;
+        Ok(Some(inner.entries.len()))
+    }
+
+    // Admits the saved transactions again, oldest first so parents come
+    // before their children. Expired ones and those the current UTXO set no
+    // longer allows are dropped. Returns the loaded, expired and invalid
+    // counts.
+    pub fn load(&self, blockchain: &Blockchain) -> Result<(usize, usize, usize), BlockchainError> {
+        let tree = blockchain.get_db().open_tree(MEMPOOL_TREE)?;
+        let mut saved: Vec<SavedEntry> = vec![];
+        for value in tree.iter().values() {
+            saved.push(bincode::deserialize(value?.as_ref())?);
+        }
+        saved.sort_by_key(|entry| entry.time);
+
+        let oldest = crate::current_timestamp().saturating_sub(GLOBAL_CONFIG.get_mempool_expiry());
+        let (mut loaded, mut expired, mut invalid) = (0, 0, 0);
+        for entry in saved {
+            if entry.time < oldest {
+                expired += 1;
+                continue;
+            }
+            match self.admit(blockchain, entry.tx, entry.time) {
+                Ok(_) => loaded += 1,
+                Err(BlockchainError::InvalidTransaction(_)) | Err(BlockchainError::Rejected(_)) => {
+                    invalid += 1
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // Only what was dropped needs to be written back
+        self.inner.write().unwrap().changed = expired + invalid > 0;
+        Ok((loaded, expired, invalid))
     }
 }
 
+// Smallest fee a transaction of `size` bytes must pay, rounded up
+fn min_relay_fee(size: usize) -> i32 {
+    let rate = GLOBAL_CONFIG.get_min_relay_fee() as i64;
+    ((size as i64 * rate + 999) / 1000) as i32
+}
+
+// Most blocks requested from one peer at a time
+const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+// A peer that has not delivered a requested block by then is stalling
+const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
 
+struct InFlight {
+    peer: String,
+    requested_at: Instant,
+}
+
+/// A downloaded block waiting for its parent, with the peer that sent it
+pub struct ParkedBlock {
+    block: Block,
+    peer: String,
+    ip: IpAddr,
+}
+
+impl ParkedBlock {
+    pub fn get_block(&self) -> &Block {
+        &self.block
+    }
+
+    pub fn get_peer(&self) -> &str {
+        self.peer.as_str()
+    }
+
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+#[derive(Default)]
+struct DownloadState {
+    peers: Vec<String>,                        // peers that sent us the headers
+    queue: VecDeque<Vec<u8>>,                  // hashes not requested yet, in height order
+    in_flight: HashMap<Vec<u8>, InFlight>,     // ( K -> block hash, V -> request )
+    parked: HashMap<String, Vec<ParkedBlock>>, // ( K -> parent hash, V -> blocks waiting for it )
+}
+
+/// Schedules block body downloads across peers
 pub struct BlockInTransit {
-    inner: RwLock<Vec<Vec<u8>>>,
+    inner: RwLock<DownloadState>,
 }
 
 impl BlockInTransit {
     pub fn new() -> BlockInTransit {
         BlockInTransit {
-            inner: RwLock::new(vec![]),
+            inner: RwLock::new(DownloadState::default()),
         }
     }
 
-    pub fn add_blocks(&self, blocks: &[Vec<u8>]) {
+    pub fn add_peer(&self, addr: &str) {
         let mut inner = self.inner.write().unwrap();
-        for hash in blocks {
-            inner.push(hash.to_vec());
+        if !inner.peers.iter().any(|peer| peer.eq(addr)) {
+            inner.peers.push(String::from(addr));
         }
     }
 
-    pub fn first(&self) -> Option<Vec<u8>> {
-        let inner = self.inner.read().unwrap();
-        if let Some(block_hash) = inner.first() {
-            return Some(block_hash.to_vec());
+    // Stops downloading from the peer, its outstanding blocks are requested
+    // from the other peers
+    pub fn remove_peer(&self, addr: &str) {
+        let mut inner = self.inner.write().unwrap();
+        inner.peers.retain(|peer| peer.ne(addr));
+        let abandoned: Vec<Vec<u8>> = inner
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer.eq(addr))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in abandoned {
+            inner.in_flight.remove(&hash);
+            inner.queue.push_front(hash);
         }
-        None
     }
 
-    pub fn remove(&self, block_hash: &[u8]) {
+    // Replaces the blocks still to request with `blocks`, in height order.
+    // Requests already in flight are kept.
+    pub fn set_wanted(&self, blocks: &[Vec<u8>]) {
         let mut inner = self.inner.write().unwrap();
-        if let Some(idx) = inner.iter().position(|x| x.eq(block_hash)) {
-            inner.remove(idx);
+        let queue: VecDeque<Vec<u8>> = blocks
+            .iter()
+            .filter(|hash| !inner.in_flight.contains_key(*hash))
+            .filter(|hash| {
+                let mut parked = inner.parked.values().flatten();
+                !parked.any(|parked| parked.block.get_hash_bytes().eq(*hash))
+            })
+            .cloned()
+            .collect();
+        inner.queue = queue;
+    }
+
+    // Assigns queued blocks to the peers with the fewest blocks in flight.
+    // Requests that timed out go back to the front of the queue and the
+    // stalling peer is dropped, unless it is the only one left.
+    pub fn next_requests(&self) -> Vec<(String, Vec<u8>)> {
+        let mut inner = self.inner.write().unwrap();
+        let timed_out: Vec<(Vec<u8>, String)> = inner
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.requested_at.elapsed() > BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, request)| (hash.clone(), request.peer.clone()))
+            .collect();
+        for (hash, peer) in timed_out {
+            inner.in_flight.remove(&hash);
+            inner.queue.push_front(hash);
+            if inner.peers.len() > 1 {
+                inner.peers.retain(|x| x.ne(&peer));
+            }
         }
+
+        let mut requests = vec![];
+        while !inner.queue.is_empty() {
+            let peer = inner
+                .peers
+                .iter()
+                .map(|peer| {
+                    let count = inner.in_flight.values().filter(|x| x.peer.eq(peer)).count();
+                    (count, peer)
+                })
+                .filter(|(count, _)| *count < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .min_by_key(|(count, _)| *count)
+                .map(|(_, peer)| peer.clone());
+            let peer = match peer {
+                Some(peer) => peer,
+                None => break,
+            };
+            let hash = inner.queue.pop_front().unwrap();
+            let request = InFlight {
+                peer: peer.clone(),
+                requested_at: Instant::now(),
+            };
+            inner.in_flight.insert(hash.clone(), request);
+            requests.push((peer, hash));
+        }
+        requests
     }
 
+    #[allow(dead_code)]
     pub fn clear(&self) {
+        *self.inner.write().unwrap() = DownloadState::default();
+    }
+
+    pub fn received(&self, block_hash: &[u8]) {
         let mut inner = self.inner.write().unwrap();
-        inner.clear();
+        inner.in_flight.remove(block_hash);
+        inner.queue.retain(|hash| hash.ne(block_hash));
     }
 
-    pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+    // Keeps a block that arrived before its parent until the parent is
+    // connected, remembering the peer it came from
+    pub fn park(&self, block: Block, peer: &str, ip: IpAddr) {
+        let mut inner = self.inner.write().unwrap();
+        let children = inner.parked.entry(block.get_pre_block_hash()).or_default();
+        if children.iter().any(|parked| parked.block.get_hash() == block.get_hash()) {
+            return;
+        }
+        let peer = String::from(peer);
+        children.push(ParkedBlock { block, peer, ip });
+    }
+
+    // Every parked block waiting for the parent, in the order they arrived
+    pub fn take_children(&self, parent_hash: &str) -> Vec<ParkedBlock> {
+        let mut inner = self.inner.write().unwrap();
+        inner.parked.remove(parent_hash).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    fn hashes(count: u8) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i]).collect()
+    }
+
+    // Makes every request in flight look older than the download timeout
+    fn expire_requests(downloads: &BlockInTransit) {
+        let requested_at = Instant::now().checked_sub(BLOCK_DOWNLOAD_TIMEOUT * 2).unwrap();
+        let mut inner = downloads.inner.write().unwrap();
+        for request in inner.in_flight.values_mut() {
+            request.requested_at = requested_at;
+        }
+    }
+
+    #[test]
+    fn requests_are_capped_per_peer() {
+        let downloads = BlockInTransit::new();
+        downloads.add_peer("a");
+        downloads.set_wanted(&hashes(20));
+        let requests = downloads.next_requests();
+        assert_eq!(requests.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert!(requests.iter().all(|(peer, _)| peer == "a"));
+        assert!(downloads.next_requests().is_empty());
+
+        downloads.received(&requests[0].1);
+        assert_eq!(downloads.next_requests(), vec![(String::from("a"), vec![16])]);
+    }
+
+    #[test]
+    fn requests_are_spread_across_peers() {
+        let downloads = BlockInTransit::new();
+        downloads.add_peer("a");
+        downloads.add_peer("b");
+        downloads.set_wanted(&hashes(4));
+        let requests = downloads.next_requests();
+        assert_eq!(requests.len(), 4);
+        assert_eq!(requests.iter().filter(|(peer, _)| peer == "a").count(), 2);
+    }
+
+    #[test]
+    fn timed_out_requests_move_to_another_peer() {
+        let downloads = BlockInTransit::new();
+        downloads.add_peer("a");
+        downloads.add_peer("b");
+        downloads.set_wanted(&hashes(1));
+        assert_eq!(downloads.next_requests(), vec![(String::from("a"), vec![0])]);
+
+        expire_requests(&downloads);
+        assert_eq!(downloads.next_requests(), vec![(String::from("b"), vec![0])]);
+        // The last peer is kept and asked again
+        expire_requests(&downloads);
+        assert_eq!(downloads.next_requests(), vec![(String::from("b"), vec![0])]);
+    }
+
+    #[test]
+    fn removed_peers_hand_their_requests_back() {
+        let downloads = BlockInTransit::new();
+        downloads.add_peer("a");
+        downloads.set_wanted(&hashes(2));
+        assert_eq!(downloads.next_requests().len(), 2);
+        downloads.remove_peer("a");
+        assert!(downloads.next_requests().is_empty());
+
+        downloads.add_peer("b");
+        let mut requests = downloads.next_requests();
+        requests.sort();
+        assert_eq!(requests, vec![(String::from("b"), vec![0]), (String::from("b"), vec![1])]);
+    }
+
+    #[test]
+    fn parked_blocks_wait_for_their_parent() {
+        let address = Address::from_pub_key_hash(&[7; 20]);
+        let bits = crate::ProofOfWork::initial_bits();
+        let children: Vec<Block> = (0..2)
+            .map(|_| {
+                let coinbase = Transaction::new_coinbase_tx(&address, 0);
+                Block::new_block(String::from("parent"), &[coinbase], 1, bits)
+            })
+            .collect();
+        assert_ne!(children[0].get_hash(), children[1].get_hash());
+        let downloads = BlockInTransit::new();
+        downloads.add_peer("a");
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        downloads.park(children[0].clone(), "a", ip);
+        downloads.park(children[1].clone(), "b", ip);
+        downloads.park(children[1].clone(), "c", ip);
+
+        // A parked block is not requested again
+        downloads.set_wanted(&[children[0].get_hash_bytes(), children[1].get_hash_bytes()]);
+        assert!(downloads.next_requests().is_empty());
+        assert!(downloads.take_children("other").is_empty());
+        // Competing children are all kept, each with the peer that sent it
+        let parked = downloads.take_children("parent");
+        let parked: Vec<(&str, &str)> =
+            parked.iter().map(|x| (x.get_block().get_hash(), x.get_peer())).collect();
+        assert_eq!(parked, [(children[0].get_hash(), "a"), (children[1].get_hash(), "b")]);
+        assert!(downloads.take_children("parent").is_empty());
     }
 }
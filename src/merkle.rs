@@ -0,0 +1,83 @@
+// Merkle tree over transaction ids
+use serde::{Deserialize, Serialize};
+
+/// Proof that a txid is a leaf of the tree with a given root: the sibling
+/// hashes from the leaf up to the root
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MerkleProof {
+    txid: Vec<u8>,
+    path: Vec<MerkleStep>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MerkleStep {
+    sibling: Vec<u8>,
+    sibling_is_left: bool,
+}
+
+impl MerkleProof {
+    pub fn get_txid(&self) -> &[u8] {
+        self.txid.as_slice()
+    }
+
+    pub fn verify(&self, merkle_root: &[u8]) -> bool {
+        let mut hash = self.txid.clone();
+        for step in &self.path {
+            hash = if step.sibling_is_left {
+                hash_pair(step.sibling.as_slice(), hash.as_slice())
+            } else {
+                hash_pair(hash.as_slice(), step.sibling.as_slice())
+            };
+        }
+        hash.eq(merkle_root)
+    }
+}
+
+// Root of the tree built from the txids. Levels with an odd number of nodes
+// pair the last node with itself, as Bitcoin does.
+pub fn merkle_root(txids: &[Vec<u8>]) -> Vec<u8> {
+    if txids.is_empty() {
+        return crate::sha256_digest(&[]);
+    }
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        level = next_level(level.as_slice());
+    }
+    level.remove(0)
+}
+
+pub fn merkle_proof(txids: &[Vec<u8>], txid: &[u8]) -> Option<MerkleProof> {
+    let mut index = txids.iter().position(|x| x.as_slice() == txid)?;
+    let mut level = txids.to_vec();
+    let mut path = vec![];
+    while level.len() > 1 {
+        let sibling_index = if index % 2 == 0 { index + 1 } else { index - 1 };
+        let sibling = level.get(sibling_index).unwrap_or(&level[index]).clone();
+        path.push(MerkleStep {
+            sibling,
+            sibling_is_left: index % 2 == 1,
+        });
+        level = next_level(level.as_slice());
+        index /= 2;
+    }
+    Some(MerkleProof {
+        txid: txid.to_vec(),
+        path,
+    })
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| {
+            let right = pair.get(1).unwrap_or(&pair[0]);
+            hash_pair(pair[0].as_slice(), right.as_slice())
+        })
+        .collect()
+}
+
+fn hash_pair(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut data = left.to_vec();
+    data.extend(right);
+    crate::sha256_digest(data.as_slice())
+}
@@ -1,50 +1,105 @@
-use std::net::SocketAddr;
-use std::sync::RwLock;
+use crate::codec::Codec;
+use crate::server::Package;
+use crate::BlockchainError;
+use std::collections::HashSet;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, RwLock};
 
+/// An established connection to a peer, known by the address it listens on
 #[derive(Clone)]
 pub struct Node {
     addr: String,
+    stream: Arc<Mutex<TcpStream>>,
 }
 
 impl Node {
-    fn new(addr: String) -> Node {
-        Node { addr }
+    pub fn new(addr: String, stream: TcpStream) -> Node {
+        Node {
+            addr,
+            stream: Arc::new(Mutex::new(stream)),
+        }
+    }
+
+    // The same connection known by another address, writes to either share
+    // one lock
+    pub fn with_addr(&self, addr: String) -> Node {
+        Node {
+            addr,
+            stream: self.stream.clone(),
+        }
     }
 
     pub fn get_addr(&self) -> String {
         self.addr.clone()
     }
 
+    #[allow(dead_code)]
     pub fn parse_socket_addr(&self) -> SocketAddr {
         self.addr.parse().unwrap()
     }
+
+    pub fn send(&self, pkg: &Package) -> Result<(), BlockchainError> {
+        let mut stream = self.stream.lock().unwrap();
+        Codec::from_config().write_package(&mut *stream, pkg)
+    }
+
+    // Closes the connection, which also ends the thread reading from it
+    pub fn disconnect(&self) {
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+    }
 }
 
 pub struct Nodes {
     inner: RwLock<Vec<Node>>,
+    connecting: RwLock<HashSet<String>>, // outbound connections being opened
 }
 
 impl Nodes {
     pub fn new() -> Nodes {
         Nodes {
             inner: RwLock::new(vec![]),
+            connecting: RwLock::new(HashSet::new()),
         }
     }
 
-    pub fn add_node(&self, addr: String) {
+    // Reserves one of `limit` slots for connecting to the peer, counting the
+    // registered connections and the ones still being opened. Returns false
+    // when the peer is already connected or being connected to, or no slot is left.
+    pub fn start_connecting(&self, addr: &str, limit: usize) -> bool {
+        let inner = self.inner.read().unwrap();
+        let mut connecting = self.connecting.write().unwrap();
+        if inner.iter().any(|x| x.get_addr().eq(addr))
+            || connecting.contains(addr)
+            || inner.len() + connecting.len() >= limit
+        {
+            return false;
+        }
+        connecting.insert(String::from(addr))
+    }
+
+    pub fn finish_connecting(&self, addr: &str) {
+        self.connecting.write().unwrap().remove(addr);
+    }
+
+    // Registers the connection unless the peer is already connected.
+    // Returns whether it was registered.
+    pub fn add_node(&self, node: Node) -> bool {
         let mut inner = self.inner.write().unwrap();
-        if let None = inner.iter().position(|x| x.get_addr().eq(addr.as_str())) {
-            inner.push(Node::new(addr));
+        if inner.iter().any(|x| x.get_addr().eq(node.get_addr().as_str())) {
+            return false;
         }
+        inner.push(node);
+        true
     }
 
     pub fn evict_node(&self, addr: &str) {
         let mut inner = self.inner.write().unwrap();
         if let Some(idx) = inner.iter().position(|x| x.get_addr().eq(addr)) {
-            inner.remove(idx);
+            inner.remove(idx).disconnect();
         }
     }
 
+    #[allow(dead_code)]
     pub fn first(&self) -> Option<Node> {
         let inner = self.inner.read().unwrap();
         if let Some(node) = inner.first() {
@@ -53,19 +108,17 @@ impl Nodes {
         None
     }
 
+    pub fn get_node(&self, addr: &str) -> Option<Node> {
+        let inner = self.inner.read().unwrap();
+        inner.iter().find(|x| x.get_addr().eq(addr)).cloned()
+    }
+
     pub fn get_nodes(&self) -> Vec<Node> {
         self.inner.read().unwrap().to_vec()
     }
 
+    #[allow(dead_code)]
     pub fn len(&self) -> usize {
         self.inner.read().unwrap().len()
     }
-
-    pub fn node_is_known(&self, addr: &str) -> bool {
-        let inner = self.inner.read().unwrap();
-        if let Some(_) = inner.iter().position(|x| x.get_addr().eq(addr)) {
-            return true;
-        }
-        false
-    }
 }
@@ -0,0 +1,156 @@
+use crate::{Blockchain, BlockchainError};
+use serde::{Deserialize, Serialize};
+
+const PEERS_TREE: &str = "peers";
+
+// Most addresses kept, gossip must not grow the store without bound
+const MAX_PEERS: usize = 1000;
+
+/// What we know about a peer address ( K -> addr, V -> PeerInfo )
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct PeerInfo {
+    last_seen: i64, // milliseconds, 0 until a handshake completed
+    successes: u32,
+    failures: u32,
+}
+
+impl PeerInfo {
+    pub fn get_last_seen(&self) -> i64 {
+        self.last_seen
+    }
+
+    pub fn get_successes(&self) -> u32 {
+        self.successes
+    }
+
+    pub fn get_failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// Peer addresses learned from the command line and from other peers,
+/// persisted so a restarted node can reconnect without a seed
+pub struct PeerStore {
+    blockchain: Blockchain,
+}
+
+impl PeerStore {
+    pub fn new(blockchain: Blockchain) -> PeerStore {
+        PeerStore { blockchain }
+    }
+
+    // Remembers the address, returns false when it was already known or
+    // there is no room left for it
+    pub fn add(&self, addr: &str) -> Result<bool, BlockchainError> {
+        if self.get(addr)?.is_some() || !self.make_room()? {
+            return Ok(false);
+        }
+        self.put(addr, &PeerInfo::default())?;
+        Ok(true)
+    }
+
+    pub fn get(&self, addr: &str) -> Result<Option<PeerInfo>, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let peers_tree = db.open_tree(PEERS_TREE)?;
+        match peers_tree.get(addr)? {
+            Some(info_bytes) => Ok(Some(bincode::deserialize(info_bytes.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn record_success(&self, addr: &str) -> Result<(), BlockchainError> {
+        let Some(mut info) = self.get_or_make_room(addr)? else {
+            return Ok(());
+        };
+        info.last_seen = crate::current_timestamp();
+        info.successes += 1;
+        self.put(addr, &info)
+    }
+
+    pub fn record_failure(&self, addr: &str) -> Result<(), BlockchainError> {
+        let Some(mut info) = self.get_or_make_room(addr)? else {
+            return Ok(());
+        };
+        info.failures += 1;
+        self.put(addr, &info)
+    }
+
+    // Known peers, the most reliable and most recently seen first
+    pub fn get_peers(&self) -> Result<Vec<(String, PeerInfo)>, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let peers_tree = db.open_tree(PEERS_TREE)?;
+        let mut peers = vec![];
+        for item in peers_tree.iter() {
+            let (addr, info_bytes) = item?;
+            let info: PeerInfo = bincode::deserialize(info_bytes.as_ref())?;
+            peers.push((String::from_utf8(addr.to_vec())?, info));
+        }
+        peers.sort_by_key(|(_, info)| {
+            let score = info.successes as i64 - info.failures as i64;
+            std::cmp::Reverse((score, info.last_seen))
+        });
+        Ok(peers)
+    }
+
+    // What is known about the address, or a fresh entry when there is room
+    // to store one
+    fn get_or_make_room(&self, addr: &str) -> Result<Option<PeerInfo>, BlockchainError> {
+        if let Some(info) = self.get(addr)? {
+            return Ok(Some(info));
+        }
+        Ok(self.make_room()?.then(PeerInfo::default))
+    }
+
+    // Makes room for one more address once the store is full by forgetting
+    // the least reliable one, unless every address connected more often than
+    // it failed. Returns whether there is room.
+    fn make_room(&self) -> Result<bool, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let peers_tree = db.open_tree(PEERS_TREE)?;
+        if peers_tree.len() < MAX_PEERS {
+            return Ok(true);
+        }
+        match self.get_peers()?.last() {
+            Some((addr, info)) if info.successes <= info.failures => {
+                peers_tree.remove(addr.as_str())?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn put(&self, addr: &str, info: &PeerInfo) -> Result<(), BlockchainError> {
+        let db = self.blockchain.get_db();
+        let peers_tree = db.open_tree(PEERS_TREE)?;
+        peers_tree.insert(addr, bincode::serialize(info)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Address;
+
+    #[test]
+    fn the_store_is_capped_and_forgets_unreliable_peers_first() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let blockchain = Blockchain::create_in(db, &Address::from_pub_key_hash(&[7; 20])).unwrap();
+        let peer_store = PeerStore::new(blockchain);
+        for port in 0..MAX_PEERS {
+            peer_store.record_success(format!("127.0.0.1:{}", port).as_str()).unwrap();
+        }
+        // Reliable peers are not pushed out by gossip
+        assert!(!peer_store.add("10.0.0.1:2001").unwrap());
+        peer_store.record_failure("10.0.0.2:2001").unwrap();
+        assert!(peer_store.get("10.0.0.2:2001").unwrap().is_none());
+
+        peer_store.record_failure("127.0.0.1:7").unwrap();
+        assert!(peer_store.add("10.0.0.1:2001").unwrap());
+        assert!(peer_store.get("127.0.0.1:7").unwrap().is_none());
+        assert_eq!(peer_store.get_peers().unwrap().len(), MAX_PEERS);
+        // The new address has not connected yet, the next one takes its place
+        assert!(peer_store.add("10.0.0.3:2001").unwrap());
+        assert!(peer_store.get("10.0.0.1:2001").unwrap().is_none());
+    }
+}
@@ -1,36 +1,71 @@
-use crate::Block;
+use crate::block::BlockHeader;
 use data_encoding::HEXLOWER;
 use num_bigint::{BigInt, Sign};
 use std::borrow::Borrow;
 use std::ops::ShlAssign;
 
-const TARGET_BITS: i32 = 8;
+// Leading zero bits of the easiest allowed target, used for the genesis block
+const POW_LIMIT_BITS: i32 = 8;
 const MAX_NONCE: i64 = i64::MAX;
+// A retarget may change the difficulty by at most this factor
+const MAX_RETARGET_FACTOR: i64 = 4;
 
 // Basic Proof of Work implementation
 pub struct ProofOfWork {
-    block: Block,
+    header: BlockHeader,
     target: BigInt,
 }
 
 impl ProofOfWork {
-    pub fn new_proof_of_work(block: Block) -> ProofOfWork {
-        let mut target = BigInt::from(1);
-        target.shl_assign(256 - TARGET_BITS);
-        ProofOfWork { block, target }
+    pub fn new_proof_of_work(header: BlockHeader) -> ProofOfWork {
+        let target = compact_to_target(header.get_bits());
+        ProofOfWork { header, target }
+    }
+
+    // Compact target of the genesis block, the lowest difficulty allowed
+    pub fn initial_bits() -> u32 {
+        target_to_compact(&pow_limit())
+    }
+
+    // Scales the previous target by how long the last window took compared to
+    // the expected timespan, Bitcoin style. Without an expected timespan the
+    // difficulty stays.
+    pub fn retarget(bits: u32, actual_timespan: i64, expected_timespan: i64) -> u32 {
+        if expected_timespan <= 0 {
+            return bits;
+        }
+        let actual_timespan = actual_timespan.clamp(
+            expected_timespan / MAX_RETARGET_FACTOR,
+            expected_timespan.saturating_mul(MAX_RETARGET_FACTOR),
+        );
+        let mut target = compact_to_target(bits) * actual_timespan / expected_timespan;
+        let limit = pow_limit();
+        if target > limit {
+            target = limit;
+        }
+        target_to_compact(&target)
+    }
+
+    // Expected number of hashes needed to meet the target, 2^256 / (target + 1)
+    pub fn block_work(bits: u32) -> BigInt {
+        let mut numerator = BigInt::from(1);
+        numerator.shl_assign(256);
+        numerator / (compact_to_target(bits) + 1)
     }
     
     fn prepare_data(&self, nonce: i64) -> Vec<u8> {
-        let pre_block_hash = self.block.get_pre_block_hash();
-        let transactions_hash = self.block.hash_transactions();
-        let timestamp = self.block.get_timestamp();
+        let pre_block_hash = self.header.get_pre_block_hash();
+        let merkle_root = self.header.get_merkle_root();
+        let timestamp = self.header.get_timestamp();
         let mut data_bytes = vec![];
+        data_bytes.extend(self.header.get_version().to_be_bytes());
         data_bytes.extend(pre_block_hash.as_bytes());
-        data_bytes.extend(transactions_hash);
+        data_bytes.extend(merkle_root);
         data_bytes.extend(timestamp.to_be_bytes());
-        data_bytes.extend(TARGET_BITS.to_be_bytes());
+        data_bytes.extend(self.header.get_bits().to_be_bytes());
         data_bytes.extend(nonce.to_be_bytes());
-        return data_bytes;
+        data_bytes.extend((self.header.get_height() as u64).to_be_bytes());
+        data_bytes
     }
     
     pub fn run(&self) -> (i64, String) {
@@ -50,14 +85,105 @@ impl ProofOfWork {
             }
         }
         println!();
-        return (nonce, HEXLOWER.encode(hash.as_slice()));
+        (nonce, HEXLOWER.encode(hash.as_slice()))
     }
-    
-    pub fn validate(&self, nonce: i64) -> bool {
-        // In a real implementation, this would validate the proof of work
-        // using the provided nonce
-        let hash = crate::sha256_digest(&nonce.to_be_bytes());
+
+    // Hex encoded header hash at the header nonce
+    pub fn hash(&self) -> String {
+        let data = self.prepare_data(self.header.get_nonce());
+        HEXLOWER.encode(crate::sha256_digest(data.as_slice()).as_slice())
+    }
+
+    // Recomputes the header hash from the header fields and checks it against
+    // the target
+    pub fn validate(&self) -> bool {
+        let data = self.prepare_data(self.header.get_nonce());
+        let hash = crate::sha256_digest(data.as_slice());
         let hash_int = BigInt::from_bytes_be(Sign::Plus, hash.as_slice());
         hash_int.lt(self.target.borrow())
     }
-} 
+}
+
+fn pow_limit() -> BigInt {
+    let mut target = BigInt::from(1);
+    target.shl_assign(256 - POW_LIMIT_BITS);
+    target
+}
+
+// Decodes a compact target: the high byte is the size in bytes, the low three
+// bytes are the most significant bytes of the target
+pub fn compact_to_target(bits: u32) -> BigInt {
+    let size = (bits >> 24) as usize;
+    let mantissa = BigInt::from(bits & 0x007f_ffff);
+    if size <= 3 {
+        mantissa >> (8 * (3 - size))
+    } else {
+        mantissa << (8 * (size - 3))
+    }
+}
+
+pub fn target_to_compact(target: &BigInt) -> u32 {
+    let (_, bytes) = target.to_bytes_be();
+    let mut size = bytes.len();
+    let mut mantissa = if size <= 3 {
+        let mut value = 0u32;
+        for byte in &bytes {
+            value = (value << 8) | *byte as u32;
+        }
+        value << (8 * (3 - size))
+    } else {
+        ((bytes[0] as u32) << 16) | ((bytes[1] as u32) << 8) | bytes[2] as u32
+    };
+    // The 0x00800000 bit is a sign bit, move it into the size byte instead
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    ((size as u32) << 24) | mantissa
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A difficulty above the lowest one, so it can move both ways
+    const BITS: u32 = 0x1d00_ffff;
+    const TIMESPAN: i64 = 200_000;
+
+    #[test]
+    fn compact_targets_round_trip() {
+        for bits in [ProofOfWork::initial_bits(), BITS, 0x1b04_04cb, 0x0312_3456, 0x0500_9234] {
+            assert_eq!(target_to_compact(&compact_to_target(bits)), bits, "{:#010x}", bits);
+        }
+        assert_eq!(ProofOfWork::initial_bits(), 0x2001_0000);
+        assert_eq!(compact_to_target(ProofOfWork::initial_bits()), pow_limit());
+        // The mantissa sign bit moves into the size byte
+        assert_eq!(target_to_compact(&BigInt::from(0x80)), 0x0200_8000);
+        assert_eq!(compact_to_target(0x0200_8000), BigInt::from(0x80));
+    }
+
+    #[test]
+    fn retarget_follows_the_timespan() {
+        assert_eq!(ProofOfWork::retarget(BITS, TIMESPAN, TIMESPAN), BITS);
+        let faster = ProofOfWork::retarget(BITS, TIMESPAN / 2, TIMESPAN);
+        assert_eq!(compact_to_target(faster), compact_to_target(BITS) / 2);
+        let slower = ProofOfWork::retarget(BITS, TIMESPAN * 2, TIMESPAN);
+        assert_eq!(compact_to_target(slower), compact_to_target(BITS) * 2);
+    }
+
+    #[test]
+    fn retarget_is_clamped() {
+        let hardest = ProofOfWork::retarget(BITS, TIMESPAN / MAX_RETARGET_FACTOR, TIMESPAN);
+        assert_eq!(ProofOfWork::retarget(BITS, 0, TIMESPAN), hardest);
+        assert_eq!(ProofOfWork::retarget(BITS, -TIMESPAN, TIMESPAN), hardest);
+        let easiest = ProofOfWork::retarget(BITS, TIMESPAN * MAX_RETARGET_FACTOR, TIMESPAN);
+        assert_eq!(ProofOfWork::retarget(BITS, TIMESPAN * 100, TIMESPAN), easiest);
+
+        // Never easier than the genesis difficulty
+        let initial_bits = ProofOfWork::initial_bits();
+        assert_eq!(ProofOfWork::retarget(initial_bits, TIMESPAN * 4, TIMESPAN), initial_bits);
+        // Without an expected timespan nothing changes
+        assert_eq!(ProofOfWork::retarget(BITS, TIMESPAN, 0), BITS);
+        assert_eq!(ProofOfWork::retarget(BITS, i64::MAX, i64::MAX), BITS);
+    }
+}
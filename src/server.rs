@@ -1,43 +1,89 @@
 use crate::{
-    Block, Blockchain, Transaction, UTXOSet, GLOBAL_CONFIG,
+    Address, Block, Blockchain, BlockchainError, Transaction, UTXOSet, GLOBAL_CONFIG,
 };
+use crate::ban_list::{BanList, BanScores, BAN_THRESHOLD};
+use crate::block::{BlockHeader, MAX_BLOCK_SIZE};
+use crate::blockchain::{self, ChainUpdate};
+use crate::codec::Codec;
 use crate::memory_pool::{BlockInTransit, MemoryPool};
-use crate::node::Nodes;
+use crate::node::{Node, Nodes};
+use crate::peer_store::PeerStore;
 use data_encoding::HEXLOWER;
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use std::error::Error;
-use std::io::{BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::io::BufReader;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::thread;
 use std::time::Duration;
 
 
 const NODE_VERSION: usize = 1;
-pub const CENTERAL_NODE: &str = "127.0.0.1:2001";
+
+// Outbound connections opened from the peer store and from gossip
+const MAX_OUTBOUND_PEERS: usize = 8;
+
+// Most addresses sent in one Addr package
+const MAX_ADDRS: usize = 1000;
 
 
 pub const TRANSACTION_THRESHOLD: usize = 2;
 
+// Room left in a mined block for the header and the coinbase
+const BLOCK_RESERVED_SIZE: usize = 1000;
+
+// Most headers sent in one Headers package, a full batch asks for more
+const MAX_HEADERS: usize = 2000;
+
+// Most block hashes sent in one Inv package, a full batch asks for more
+const MAX_INV_BLOCKS: usize = 500;
 
-static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(|| {
-    let nodes = Nodes::new();
-    
-    nodes.add_node(String::from(CENTERAL_NODE));
-    return nodes;
-});
 
+static GLOBAL_NODES: Lazy<Nodes> = Lazy::new(Nodes::new);
 
-static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(|| MemoryPool::new());
 
+static GLOBAL_MEMORY_POOL: Lazy<MemoryPool> = Lazy::new(MemoryPool::new);
 
-static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(|| BlockInTransit::new());
+
+static GLOBAL_BLOCKS_IN_TRANSIT: Lazy<BlockInTransit> = Lazy::new(BlockInTransit::new);
+
+static GLOBAL_BAN_SCORES: Lazy<BanScores> = Lazy::new(BanScores::new);
+
+// Ban score added for an invalid or malformed block or header
+const INVALID_BLOCK_SCORE: u32 = 100;
+// Ban score added for an invalid or malformed transaction
+const INVALID_TX_SCORE: u32 = 10;
+// Ban score added for broken framing, oversized messages and packages out of order
+const PROTOCOL_VIOLATION_SCORE: u32 = 25;
 
 
 const TCP_WRITE_TIMEOUT: u64 = 1000;
 
+// Milliseconds to wait for a peer to accept a connection
+const TCP_CONNECT_TIMEOUT: u64 = 3000;
+
+// Connection attempts before a peer is given up as unreachable
+const CONNECT_ATTEMPTS: u32 = 3;
+
+// Milliseconds before the second connection attempt, doubled for each later one
+const CONNECT_BACKOFF: u64 = 200;
+
+// Seconds between checks for stalled block downloads
+const DOWNLOAD_CHECK_INTERVAL: u64 = 1;
+
+// Seconds between keepalive pings to every connected peer
+const PING_INTERVAL: u64 = 30;
+
+// A connection that received nothing for this many seconds is closed
+const IDLE_TIMEOUT: u64 = 90;
+
+// Seconds a short lived connection waits for the handshake to complete
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+// Seconds between expiring old transactions and saving the memory pool
+const MEMPOOL_SAVE_INTERVAL: u64 = 5;
+
 pub struct Server {
     blockchain: Blockchain,
 }
@@ -47,24 +93,95 @@ impl Server {
         Server { blockchain }
     }
 
-    pub fn run(&self, addr: &str) {
-        let listener = TcpListener::bind(addr).unwrap();
+    // Peers to connect to at startup: only the --connect peers when given,
+    // otherwise the seeds followed by the most reliable stored peers
+    fn initial_peers(&self, addr: &str) -> Result<Vec<String>, BlockchainError> {
+        let connect_peers = GLOBAL_CONFIG.get_connect_peers();
+        if !connect_peers.is_empty() {
+            return Ok(connect_peers);
+        }
+        let peer_store = PeerStore::new(self.blockchain.clone());
+        let ban_list = BanList::new(self.blockchain.clone());
+        let mut candidates = GLOBAL_CONFIG.get_seed_peers();
+        for peer in &candidates {
+            peer_store.add(peer.as_str())?;
+        }
+        for (peer, _) in peer_store.get_peers()? {
+            if !candidates.contains(&peer) {
+                candidates.push(peer);
+            }
+        }
+        let mut peers = vec![];
+        for peer in candidates {
+            // Unparsable addresses are reported when connecting to them
+            let banned = match peer.parse::<SocketAddr>() {
+                Ok(socket_addr) => ban_list.is_banned(socket_addr.ip())?,
+                Err(_) => false,
+            };
+            if peer.ne(addr) && !banned {
+                peers.push(peer);
+            }
+        }
+        peers.truncate(MAX_OUTBOUND_PEERS);
+        Ok(peers)
+    }
 
-        if addr.eq(CENTERAL_NODE) == false {
-            let best_height = self.blockchain.get_best_height();
-            send_version(CENTERAL_NODE, best_height);
+    pub fn run(&self, addr: &str) -> Result<(), BlockchainError> {
+        let utxo_set = UTXOSet::new(self.blockchain.clone());
+        if !utxo_set.is_consistent()? {
+            warn!("The UTXO set does not match the chain tip, reindexing");
+            utxo_set.reindex()?;
+        }
+        let (loaded, expired, invalid) = GLOBAL_MEMORY_POOL.load(&self.blockchain)?;
+        info!(
+            "Loaded {} transactions into the memory pool, {} expired, {} no longer valid",
+            loaded, expired, invalid
+        );
+        let listener = TcpListener::bind(addr)
+            .map_err(|e| BlockchainError::NetworkError(format!("failed to bind {}: {}", addr, e)))?;
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_secs(DOWNLOAD_CHECK_INTERVAL));
+            request_blocks();
+        });
+        thread::spawn(|| loop {
+            thread::sleep(Duration::from_secs(PING_INTERVAL));
+            for node in GLOBAL_NODES.get_nodes() {
+                if let Err(e) = send_ping(&node) {
+                    warn!("Failed to ping {}: {}", node.get_addr(), e);
+                }
+            }
+        });
+        // There is no shutdown hook, a stopped node loses at most one interval
+        let blockchain = self.blockchain.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(MEMPOOL_SAVE_INTERVAL));
+            for txid_hex in GLOBAL_MEMORY_POOL.expire() {
+                info!("Expired transaction {} from the memory pool", txid_hex);
+            }
+            match GLOBAL_MEMORY_POOL.save(&blockchain) {
+                Ok(Some(count)) => info!("Saved the memory pool with {} transactions", count),
+                Ok(None) => {}
+                Err(e) => error!("Failed to save the memory pool: {}", e),
+            }
+        });
+
+        for peer in self.initial_peers(addr)? {
+            connect_to_peer(self.blockchain.clone(), peer.as_str())?;
         }
         for stream in listener.incoming() {
             let blockchain = self.blockchain.clone();
-            thread::spawn(|| match stream {
+            thread::spawn(move || match stream {
                 Ok(stream) => {
-                    // TODO: Implement connection handling
+                    if let Err(e) = serve_inbound(blockchain, stream) {
+                        error!("Error on serving client: {}", e);
+                    }
                 }
                 Err(e) => {
-                    eprintln!("Connection error: {}", e);
+                    error!("Connection failed: {}", e);
                 }
             });
         }
+        Ok(())
     }
 }
 
@@ -82,12 +199,28 @@ pub enum Package {
     },
     GetBlocks {
         addr_from: String,
+        locator: Vec<String>,
+    },
+    Addr {
+        addr_from: String,
+        addrs: Vec<String>,
+    },
+    GetAddr {
+        addr_from: String,
     },
     GetData {
         addr_from: String,
         op_type: OpType,
         id: Vec<u8>,
     },
+    GetHeaders {
+        addr_from: String,
+        locator: Vec<String>,
+    },
+    Headers {
+        addr_from: String,
+        headers: Vec<Vec<u8>>,
+    },
     Inv {
         addr_from: String,
         op_type: OpType,
@@ -102,212 +235,514 @@ pub enum Package {
         version: usize,
         best_height: usize,
     },
+    VerAck {
+        addr_from: String,
+    },
+    Ping {
+        addr_from: String,
+        nonce: u64,
+    },
+    Pong {
+        addr_from: String,
+        nonce: u64,
+    },
+    Reject {
+        addr_from: String,
+        txid: Vec<u8>,
+        reason: String,
+    },
+}
+
+impl Package {
+    // Name of the package in the frame header
+    pub fn command(&self) -> &'static str {
+        match self {
+            Package::Block { .. } => "block",
+            Package::GetBlocks { .. } => "getblocks",
+            Package::Addr { .. } => "addr",
+            Package::GetAddr { .. } => "getaddr",
+            Package::GetData { .. } => "getdata",
+            Package::GetHeaders { .. } => "getheaders",
+            Package::Headers { .. } => "headers",
+            Package::Inv { .. } => "inv",
+            Package::Tx { .. } => "tx",
+            Package::Version { .. } => "version",
+            Package::VerAck { .. } => "verack",
+            Package::Ping { .. } => "ping",
+            Package::Pong { .. } => "pong",
+            Package::Reject { .. } => "reject",
+        }
+    }
+}
+
+fn send_get_addr(node: &Node) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
+    send_data(
+        node,
+        Package::GetAddr {
+            addr_from: node_addr,
+        },
+    )
 }
 
-fn send_get_data(addr: &str, op_type: OpType, id: &[u8]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+fn send_addr(node: &Node, addrs: Vec<String>) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
     send_data(
-        socket_addr,
+        node,
+        Package::Addr {
+            addr_from: node_addr,
+            addrs,
+        },
+    )
+}
+
+fn send_get_data(node: &Node, op_type: OpType, id: &[u8]) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+    send_data(
+        node,
         Package::GetData {
             addr_from: node_addr,
             op_type,
             id: id.to_vec(),
         },
-    );
+    )
 }
 
-fn send_inv(addr: &str, op_type: OpType, blocks: &[Vec<u8>]) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    
+fn send_inv(node: &Node, op_type: OpType, blocks: &[Vec<u8>]) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
     send_data(
-        socket_addr,
+        node,
         Package::Inv {
             addr_from: node_addr,
             op_type,
             items: blocks.to_vec(),
         },
-    );
+    )
 }
 
-fn send_block(addr: &str, block: &Block) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
+fn send_get_headers(node: &Node, locator: Vec<String>) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
+    send_data(
+        node,
+        Package::GetHeaders {
+            addr_from: node_addr,
+            locator,
+        },
+    )
+}
+
+fn send_get_blocks(node: &Node, locator: Vec<String>) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
+    send_data(
+        node,
+        Package::GetBlocks {
+            addr_from: node_addr,
+            locator,
+        },
+    )
+}
+
+fn send_headers(node: &Node, headers: &[BlockHeader]) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
+    send_data(
+        node,
+        Package::Headers {
+            addr_from: node_addr,
+            headers: headers.iter().map(|header| header.serialize()).collect(),
+        },
+    )
+}
+
+fn send_block(node: &Node, block: &Block) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
     let block_data = block.serialize();
-    
+
     send_data(
-        socket_addr,
+        node,
         Package::Block {
             addr_from: node_addr,
             block: block_data,
         },
-    );
+    )
 }
 
-pub fn send_tx(addr: &str, tx: &Transaction) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    
+fn send_tx(node: &Node, tx: &Transaction) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
     send_data(
-        socket_addr,
+        node,
         Package::Tx {
             addr_from: node_addr,
             transaction: tx.serialize(),
         },
-    );
+    )
 }
 
-fn send_version(addr: &str, height: usize) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    
+fn send_ping(node: &Node) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
     send_data(
-        socket_addr,
-        Package::Version {
+        node,
+        Package::Ping {
             addr_from: node_addr,
-            version: NODE_VERSION,
-            best_height: height,
+            nonce: crate::current_timestamp() as u64,
         },
-    );
+    )
 }
 
-fn send_get_blocks(addr: &str) {
-    let socket_addr = addr.parse().unwrap();
-    let node_addr = GLOBAL_CONFIG.get_node_addr().parse().unwrap();
-    
+fn send_pong(node: &Node, nonce: u64) -> Result<(), BlockchainError> {
+    let node_addr = GLOBAL_CONFIG.get_node_addr();
+
     send_data(
-        socket_addr,
-        Package::GetBlocks {
+        node,
+        Package::Pong {
             addr_from: node_addr,
+            nonce,
         },
-    );
+    )
 }
 
-fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+fn version_package(best_height: usize) -> Package {
+    Package::Version {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        version: NODE_VERSION,
+        best_height,
+    }
+}
+
+// Version of a command line client, which has no address to be reached at
+fn client_version_package() -> Package {
+    Package::Version {
+        addr_from: String::new(),
+        version: NODE_VERSION,
+        best_height: 0,
+    }
+}
+
+fn verack_package() -> Package {
+    Package::VerAck {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+    }
+}
+
+fn reject_package(txid: &[u8], err: &BlockchainError) -> Package {
+    let reason = match err {
+        BlockchainError::InvalidTransaction(msg) | BlockchainError::Rejected(msg) => msg.clone(),
+        err => err.to_string(),
+    };
+    Package::Reject {
+        addr_from: GLOBAL_CONFIG.get_node_addr(),
+        txid: txid.to_vec(),
+        reason,
+    }
+}
+
+// Opens a long lived connection to the peer and serves it on its own thread
+fn connect_to_peer(blockchain: Blockchain, addr: &str) -> Result<(), BlockchainError> {
+    if let Ok(socket_addr) = parse_addr(addr)
+        && BanList::new(blockchain.clone()).is_banned(socket_addr.ip())?
+    {
+        info!("Not connecting to banned peer {}", addr);
+        return Ok(());
+    }
+    match parse_addr(addr).and_then(connect) {
+        Ok(stream) => {
+            thread::spawn(move || {
+                if let Err(e) = serve(blockchain, stream, true) {
+                    error!("Error on serving client: {}", e);
+                }
+            });
+        }
+        Err(e) => {
+            error!("Failed to connect to {}: {}", addr, e);
+            PeerStore::new(blockchain).record_failure(addr)?;
+        }
+    }
+    Ok(())
+}
+
+/// Progress of the Version/VerAck exchange on one connection
+#[derive(Default)]
+struct Handshake {
+    peer: Option<(String, usize)>, // listening address and best height from its Version
+    verack_received: bool,
+}
+
+impl Handshake {
+    fn is_established(&self) -> bool {
+        self.peer.is_some() && self.verack_received
+    }
+
+    // Command line clients do not listen and announce an empty address
+    fn is_client(&self) -> bool {
+        matches!(&self.peer, Some((addr, _)) if addr.is_empty())
+    }
+
+    fn get_peer_addr(&self) -> Option<String> {
+        self.peer.as_ref().map(|(addr, _)| addr.clone()).filter(|addr| !addr.is_empty())
+    }
+}
+
+/// Removes the connection from the registered nodes when serving it ends
+struct Registration(Option<String>);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(addr) = self.0.take() {
+            info!("Disconnected from peer {}", addr);
+            GLOBAL_NODES.evict_node(addr.as_str());
+        }
+    }
+}
+
+// Serves an accepted connection unless it comes from a banned IP
+fn serve_inbound(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    let ip = stream.peer_addr()?.ip();
+    if BanList::new(blockchain.clone()).is_banned(ip)? {
+        info!("Refused connection from banned peer {}", ip);
+        let _ = stream.shutdown(Shutdown::Both);
+        return Ok(());
+    }
+    serve(blockchain, stream, false)
+}
+
+// Serves one connection until the peer closes it, goes idle or misbehaves.
+// Outbound connections start the handshake, inbound ones answer it.
+fn serve(blockchain: Blockchain, stream: TcpStream, outbound: bool) -> Result<(), Box<dyn Error>> {
     let peer_addr = stream.peer_addr()?;
-    let reader = BufReader::new(&stream);
-    let pkg_reader = Deserializer::from_reader(reader).into_iter::<Package>();
-    for pkg in pkg_reader {
-        let pkg = pkg?;
+    let peer_ip = peer_addr.ip();
+    stream.set_read_timeout(Some(Duration::from_secs(IDLE_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)))?;
+    let codec = Codec::from_config();
+    // Replies go back on the connection the request arrived on, and every
+    // write to it goes through the lock of this one node
+    let connection = Node::new(peer_addr.to_string(), stream.try_clone()?);
+    let mut handshake = Handshake::default();
+    let mut registration = Registration(None);
+    if outbound {
+        connection.send(&version_package(blockchain.get_best_height()?))?;
+    }
+    let mut reader = BufReader::new(&stream);
+    loop {
+        // The peer is known by the address it listens on once the handshake
+        // tells it, before that by the connection. Misbehaviour is held
+        // against the IP it connects from either way.
+        let peer = handshake.get_peer_addr().unwrap_or_else(|| peer_addr.to_string());
+        let pkg = match codec.read_package(&mut reader) {
+            Ok(Some(pkg)) => pkg,
+            Ok(None) => break,
+            Err(e) => {
+                if let BlockchainError::ProtocolError(_) = e {
+                    misbehaving(&blockchain, peer.as_str(), peer_ip, PROTOCOL_VIOLATION_SCORE, &e)?;
+                }
+                return Err(Box::new(e));
+            }
+        };
         info!("Receive request from {}: {:?}", peer_addr, pkg);
+        let is_handshake = matches!(pkg, Package::Version { .. } | Package::VerAck { .. });
+        if !is_handshake && !handshake.is_established() {
+            let e = BlockchainError::ProtocolError(format!(
+                "{} package before the handshake",
+                pkg.command()
+            ));
+            misbehaving(&blockchain, peer.as_str(), peer_ip, PROTOCOL_VIOLATION_SCORE, &e)?;
+            return Err(Box::new(e));
+        }
         match pkg {
-            Package::Block { addr_from, block } => {
-                let block = Block::deserialize(block.as_slice());
-                blockchain.add_block(&block);
-                info!("Added block {}", block.get_hash());
-
-                if GLOBAL_BLOCKS_IN_TRANSIT.len() > 0 {
-                
-                    let block_hash = GLOBAL_BLOCKS_IN_TRANSIT.first().unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, &block_hash);
-                 
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash.as_slice());
-                } else {
-                   
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+            Package::Block { block, .. } => {
+                let block = match Block::deserialize(block.as_slice()) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        misbehaving(&blockchain, peer.as_str(), peer_ip, INVALID_BLOCK_SCORE, &e)?;
+                        return Err(Box::new(e));
+                    }
+                };
+                GLOBAL_BLOCKS_IN_TRANSIT.received(block.get_hash_bytes().as_slice());
+                let result = connect_downloaded_block(&blockchain, block, peer.as_str(), peer_ip);
+                if let Err(e) = result {
+                    reject_block(&blockchain, peer.as_str(), peer_ip, &e)?;
+                    request_blocks();
+                    return Err(Box::new(e));
                 }
+                request_blocks();
             }
-            Package::GetBlocks { addr_from } => {
-                let blocks = blockchain.get_block_hashes();
-                send_inv(addr_from.as_str(), OpType::Block, &blocks);
+            Package::GetBlocks { locator, .. } => {
+                // Only the blocks after the fork point, the requester asks
+                // again with the last hash when the batch is full
+                let blocks = blockchain.get_block_hashes_after(&locator, MAX_INV_BLOCKS)?;
+                if !blocks.is_empty() {
+                    send_inv(&connection, OpType::Block, &blocks)?;
+                }
             }
-            Package::GetData {
-                addr_from,
-                op_type,
-                id,
-            } => match op_type {
+            Package::GetData { op_type, id, .. } => match op_type {
                 OpType::Block => {
-                    if let Some(block) = blockchain.get_block(id.as_slice()) {
-                        send_block(addr_from.as_str(), &block);
+                    if let Some(block) = blockchain.get_block(id.as_slice())? {
+                        send_block(&connection, &block)?;
                     }
                 }
                 OpType::Tx => {
                     let txid_hex = HEXLOWER.encode(id.as_slice());
                     if let Some(tx) = GLOBAL_MEMORY_POOL.get(txid_hex.as_str()) {
-                        send_tx(addr_from.as_str(), &tx);
+                        send_tx(&connection, &tx)?;
                     }
                 }
             },
-            Package::Inv {
-                addr_from,
-                op_type,
-                items,
-            } => match op_type {
+            Package::GetHeaders { locator, .. } => {
+                let headers = blockchain.get_headers_after(&locator, MAX_HEADERS)?;
+                send_headers(&connection, &headers)?;
+            }
+            Package::Headers { headers, .. } => {
+                let mut last_hash = None;
+                for header in &headers {
+                    let result = BlockHeader::deserialize(header.as_slice())
+                        .and_then(|header| blockchain.add_header(&header).map(|_| header));
+                    let header = match result {
+                        Ok(header) => header,
+                        // A clock ahead of ours or another view of the difficulty
+                        // is not held against the peer, its later headers are
+                        // skipped
+                        Err(e) if blockchain::is_honest_header_failure(&e) => {
+                            warn!("Ignoring headers from {}: {}", peer, e);
+                            last_hash = None;
+                            break;
+                        }
+                        Err(e) => {
+                            error!("Rejected header from {}: {}", peer, e);
+                            if let BlockchainError::ValidationError(_)
+                            | BlockchainError::DeserializationError(_) = e
+                            {
+                                let score = INVALID_BLOCK_SCORE;
+                                misbehaving(&blockchain, peer.as_str(), peer_ip, score, &e)?;
+                            }
+                            GLOBAL_BLOCKS_IN_TRANSIT.remove_peer(peer.as_str());
+                            GLOBAL_NODES.evict_node(peer.as_str());
+                            return Err(Box::new(e));
+                        }
+                    };
+                    last_hash = Some(header.get_hash());
+                }
+                // Bodies are only fetched for the chain with the most work
+                if !headers.is_empty() {
+                    GLOBAL_BLOCKS_IN_TRANSIT.add_peer(peer.as_str());
+                }
+                GLOBAL_BLOCKS_IN_TRANSIT.set_wanted(&blockchain.get_missing_block_hashes()?);
+                request_blocks();
+                if headers.len() == MAX_HEADERS
+                    && let Some(last_hash) = last_hash
+                {
+                    let locator = blockchain.get_continuation_locator(last_hash)?;
+                    send_get_headers(&connection, locator)?;
+                }
+            }
+            Package::Inv { op_type, items, .. } => match op_type {
               
                 OpType::Block => {
-                
-                    GLOBAL_BLOCKS_IN_TRANSIT.add_blocks(items.as_slice());
-
-                  
-                    let block_hash = items.get(0).unwrap();
-                    send_get_data(addr_from.as_str(), OpType::Block, block_hash);
-               
-                    GLOBAL_BLOCKS_IN_TRANSIT.remove(block_hash);
+                    // Unknown blocks are validated by their headers before
+                    // any body is downloaded
+                    let mut unknown = false;
+                    for hash in &items {
+                        unknown |= blockchain.get_block(hash)?.is_none();
+                    }
+                    if unknown {
+                        send_get_headers(&connection, blockchain.get_block_locator()?)?;
+                    }
+                    if items.len() == MAX_INV_BLOCKS {
+                        let last_hash = String::from_utf8(items.last().unwrap().clone())?;
+                        let locator = blockchain.get_continuation_locator(last_hash)?;
+                        send_get_blocks(&connection, locator)?;
+                    }
                 }
                 OpType::Tx => {
-                    let txid = items.get(0).unwrap();
-                    let txid_hex = HEXLOWER.encode(txid);
+                    for txid in &items {
+                        let txid_hex = HEXLOWER.encode(txid);
 
-                    if GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) == false {
-                        send_get_data(addr_from.as_str(), OpType::Tx, txid);
+                        if !GLOBAL_MEMORY_POOL.contains(txid_hex.as_str()) {
+                            send_get_data(&connection, OpType::Tx, txid)?;
+                        }
                     }
                 }
             },
-            Package::Tx {
-                addr_from,
-                transaction,
-            } => {
-          
-                let tx = Transaction::deserialize(transaction.as_slice());
+            Package::Tx { transaction, .. } => {
+                let tx = match Transaction::deserialize(transaction.as_slice()) {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        error!("Rejected transaction from {}: {}", peer, e);
+                        misbehaving(&blockchain, peer.as_str(), peer_ip, INVALID_TX_SCORE, &e)?;
+                        continue;
+                    }
+                };
                 let txid = tx.get_id_bytes();
-                GLOBAL_MEMORY_POOL.add(tx);
+                // The reason goes back on this connection, to whoever submitted it
+                let is_new = match GLOBAL_MEMORY_POOL.accept(&blockchain, tx) {
+                    Ok(is_new) => is_new,
+                    Err(e @ BlockchainError::InvalidTransaction(_)) => {
+                        error!("Rejected transaction from {}: {}", peer, e);
+                        connection.send(&reject_package(&txid, &e))?;
+                        misbehaving(&blockchain, peer.as_str(), peer_ip, INVALID_TX_SCORE, &e)?;
+                        continue;
+                    }
+                    Err(e @ BlockchainError::Rejected(_)) => {
+                        info!("Rejected transaction from {}: {}", peer, e);
+                        connection.send(&reject_package(&txid, &e))?;
+                        continue;
+                    }
+                    Err(e) => return Err(Box::new(e)),
+                };
 
                 let node_addr = GLOBAL_CONFIG.get_node_addr();
-          
-                if node_addr.eq(CENTERAL_NODE) {
+                // Every node relays transactions it has not seen before
+                if is_new {
                     let nodes = GLOBAL_NODES.get_nodes();
                     for node in &nodes {
                         if node_addr.eq(node.get_addr().as_str()) {
                             continue;
                         }
-                        if addr_from.eq(node.get_addr().as_str()) {
+                        if peer.eq(&node.get_addr()) {
                             continue;
                         }
-                        send_inv(node.get_addr().as_str(), OpType::Tx, &vec![txid.clone()])
+                        let txid = std::slice::from_ref(&txid);
+                        if let Err(e) = send_inv(node, OpType::Tx, txid) {
+                            warn!("Failed to relay a transaction to {}: {}", node.get_addr(), e);
+                        }
                     }
                 }
             
                 if GLOBAL_MEMORY_POOL.len() >= TRANSACTION_THRESHOLD && GLOBAL_CONFIG.is_miner() {
                 
-                    let mining_address = GLOBAL_CONFIG.get_mining_addr().unwrap();
-                    let coinbase_tx = Transaction::new_coinbase_tx(mining_address.as_str());
-                    let mut txs = GLOBAL_MEMORY_POOL.get_all();
-                    txs.push(coinbase_tx);
+                    let mining_address: Address = GLOBAL_CONFIG.get_mining_addr().unwrap().parse()?;
+                    let (mut txs, fees) =
+                        GLOBAL_MEMORY_POOL.select_for_block(MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE);
+                    txs.push(Transaction::new_coinbase_tx(&mining_address, fees));
 
                  
-                    let new_block = blockchain.mine_block(&txs);
-                    let utxo_set = UTXOSet::new(blockchain.clone());
-                    utxo_set.reindex();
+                    // A failure is not the fault of the peer, the pool is kept
+                    let new_block = match blockchain.mine_block(&txs) {
+                        Ok(new_block) => new_block,
+                        Err(e) => {
+                            error!("Failed to mine a block: {}", e);
+                            continue;
+                        }
+                    };
                     info!("New block {} is mined!", new_block.get_hash());
 
                
-                    for tx in &txs {
-                        let txid_hex = HEXLOWER.encode(tx.get_id());
-                        GLOBAL_MEMORY_POOL.remove(txid_hex.as_str());
-                    }
+                    GLOBAL_MEMORY_POOL.remove_confirmed(&new_block);
                
                     let nodes = GLOBAL_NODES.get_nodes();
                     for node in &nodes {
                         if node_addr.eq(node.get_addr().as_str()) {
                             continue;
                         }
-                        send_inv(
-                            node.get_addr().as_str(),
-                            OpType::Block,
-                            &vec![new_block.get_hash_bytes()],
-                        );
+                        let items = [new_block.get_hash_bytes()];
+                        if let Err(e) = send_inv(node, OpType::Block, &items) {
+                            warn!("Failed to announce block to {}: {}", node.get_addr(), e);
+                        }
                     }
                 }
             }
@@ -317,16 +752,75 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
                 best_height,
             } => {
                 info!("version = {}, best_height = {}", version, best_height);
-                let local_best_height = blockchain.get_best_height();
-                if local_best_height < best_height {
-                    send_get_blocks(addr_from.as_str());
+                if handshake.peer.is_some() {
+                    let e =
+                        BlockchainError::ProtocolError(String::from("duplicate version package"));
+                    misbehaving(&blockchain, peer.as_str(), peer_ip, PROTOCOL_VIOLATION_SCORE, &e)?;
+                    return Err(Box::new(e));
+                }
+                if !outbound {
+                    connection.send(&version_package(blockchain.get_best_height()?))?;
                 }
-                if local_best_height > best_height {
-                    send_version(addr_from.as_str(), blockchain.get_best_height());
+                connection.send(&verack_package())?;
+                handshake.peer = Some((addr_from, best_height));
+                if handshake.is_established() {
+                    registration.0 = on_connected(&blockchain, &connection, &handshake)?;
+                }
+            }
+            Package::VerAck { .. } => {
+                if handshake.verack_received {
+                    continue;
                 }
-             
-                if GLOBAL_NODES.node_is_known(peer_addr.to_string().as_str()) == false {
-                    GLOBAL_NODES.add_node(addr_from);
+                handshake.verack_received = true;
+                if handshake.is_established() {
+                    registration.0 = on_connected(&blockchain, &connection, &handshake)?;
+                }
+            }
+            Package::Ping { nonce, .. } => {
+                send_pong(&connection, nonce)?;
+            }
+            Package::Pong { .. } => {}
+            Package::Reject { txid, reason, .. } => {
+                warn!("Peer {} rejected {}: {}", peer, HEXLOWER.encode(&txid), reason);
+            }
+            Package::GetAddr { .. } => {
+                let addrs: Vec<String> = PeerStore::new(blockchain.clone())
+                    .get_peers()?
+                    .into_iter()
+                    .filter(|(addr, info)| info.get_successes() > 0 && addr.ne(&peer))
+                    .map(|(addr, _)| addr)
+                    .take(MAX_ADDRS)
+                    .collect();
+                send_addr(&connection, addrs)?;
+            }
+            Package::Addr { addrs, .. } => {
+                let peer_store = PeerStore::new(blockchain.clone());
+                let node_addr = GLOBAL_CONFIG.get_node_addr();
+                for addr in addrs.iter().take(MAX_ADDRS) {
+                    let Ok(socket_addr) = addr.parse::<SocketAddr>() else {
+                        continue;
+                    };
+                    if addr.eq(&node_addr)
+                        || BanList::new(blockchain.clone()).is_banned(socket_addr.ip())?
+                    {
+                        continue;
+                    }
+                    peer_store.add(addr.as_str())?;
+                    // Peers given with --connect are the only ones we connect to
+                    if GLOBAL_CONFIG.get_connect_peers().is_empty()
+                        && GLOBAL_NODES.start_connecting(addr.as_str(), MAX_OUTBOUND_PEERS)
+                    {
+                        // Connecting may wait on an unreachable address, which
+                        // must not hold up reading from this peer
+                        let blockchain = blockchain.clone();
+                        let addr = addr.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = connect_to_peer(blockchain, addr.as_str()) {
+                                error!("Failed to connect to {}: {}", addr, e);
+                            }
+                            GLOBAL_NODES.finish_connecting(addr.as_str());
+                        });
+                    }
                 }
             }
         }
@@ -335,17 +829,265 @@ fn serve(blockchain: Blockchain, stream: TcpStream) -> Result<(), Box<dyn Error>
     Ok(())
 }
 
+// Registers the connection once the handshake completes and starts syncing
+// when the peer is ahead. Returns the address it was registered under.
+fn on_connected(
+    blockchain: &Blockchain,
+    connection: &Node,
+    handshake: &Handshake,
+) -> Result<Option<String>, Box<dyn Error>> {
+    // A command line client is only served, there is nothing to ask it for
+    if handshake.is_client() {
+        info!("Connected to client {}", connection.get_addr());
+        return Ok(None);
+    }
+    let (addr, best_height) = handshake.peer.clone().unwrap();
+    info!("Connected to peer {}", addr);
+    // A peer announcing our own address is this node
+    if addr.eq(&GLOBAL_CONFIG.get_node_addr()) {
+        return Ok(None);
+    }
+    PeerStore::new(blockchain.clone()).record_success(addr.as_str())?;
+    let mut registered = None;
+    let node = connection.with_addr(addr.clone());
+    if GLOBAL_NODES.add_node(node.clone()) {
+        registered = Some(addr.clone());
+    }
+    send_get_addr(&node)?;
+    if blockchain.get_best_height()? < best_height {
+        send_get_blocks(&node, blockchain.get_block_locator()?)?;
+    }
+    Ok(registered)
+}
+
+// Raises the ban score of the IP the peer connects from and bans the IP once
+// the score reaches the threshold. `addr` is how the peer is known to the
+// node list and the download scheduler.
+fn misbehaving(
+    blockchain: &Blockchain,
+    addr: &str,
+    ip: IpAddr,
+    score: u32,
+    reason: &BlockchainError,
+) -> Result<(), BlockchainError> {
+    let total = GLOBAL_BAN_SCORES.add(ip, score);
+    warn!("Peer {} at {} misbehaved, ban score {}: {}", addr, ip, total, reason);
+    if total < BAN_THRESHOLD {
+        return Ok(());
+    }
+    let until = crate::current_timestamp().saturating_add(GLOBAL_CONFIG.get_ban_time());
+    BanList::new(blockchain.clone()).ban(ip, until)?;
+    GLOBAL_BAN_SCORES.remove(ip);
+    GLOBAL_BLOCKS_IN_TRANSIT.remove_peer(addr);
+    GLOBAL_NODES.evict_node(addr);
+    warn!("Banned peer {} at {} until {}", addr, ip, until);
+    Ok(())
+}
+
+// Connects the block and any downloaded descendants that were waiting for it.
+// A block whose parent body has not arrived yet is kept until it does. Only
+// an error of this block is returned, a waiting descendant that fails is
+// charged to the peer it came from.
+fn connect_downloaded_block(
+    blockchain: &Blockchain,
+    block: Block,
+    peer: &str,
+    ip: IpAddr,
+) -> Result<(), BlockchainError> {
+    let parent_hash = block.get_pre_block_hash();
+    if blockchain.get_block(parent_hash.as_bytes())?.is_none()
+        && blockchain.get_header(parent_hash.as_str())?.is_some()
+    {
+        GLOBAL_BLOCKS_IN_TRANSIT.park(block, peer, ip);
+        // The parent may have been connected while the block was parked
+        if blockchain.get_block(parent_hash.as_bytes())?.is_some() {
+            connect_parked_blocks(blockchain, parent_hash)?;
+        }
+        return Ok(());
+    }
+    add_downloaded_block(blockchain, &block)?;
+    connect_parked_blocks(blockchain, String::from(block.get_hash()))
+}
 
-fn send_data(addr: SocketAddr, pkg: Package) {
+fn add_downloaded_block(blockchain: &Blockchain, block: &Block) -> Result<(), BlockchainError> {
+    let chain_update = blockchain.add_block(block)?;
+    info!("Added block {}", block.get_hash());
+    update_memory_pool(blockchain, &chain_update)
+}
+
+// Connects the parked descendants of a stored block, every child of a parent
+// in the order they arrived
+fn connect_parked_blocks(
+    blockchain: &Blockchain,
+    parent_hash: String,
+) -> Result<(), BlockchainError> {
+    let mut parents = vec![parent_hash];
+    while let Some(parent_hash) = parents.pop() {
+        for parked in GLOBAL_BLOCKS_IN_TRANSIT.take_children(parent_hash.as_str()) {
+            let block = parked.get_block();
+            if let Err(e) = add_downloaded_block(blockchain, block) {
+                // Blocks waiting for it are downloaded again after a valid body
+                let mut failed = vec![String::from(block.get_hash())];
+                while let Some(hash) = failed.pop() {
+                    let children = GLOBAL_BLOCKS_IN_TRANSIT.take_children(hash.as_str());
+                    failed.extend(children.iter().map(|x| String::from(x.get_block().get_hash())));
+                }
+                reject_block(blockchain, parked.get_peer(), parked.get_ip(), &e)?;
+                continue;
+            }
+            parents.push(String::from(block.get_hash()));
+        }
+    }
+    Ok(())
+}
+
+// Stops downloading from a peer that sent an invalid block. The block was
+// taken off the schedule on arrival, another peer may still have the valid
+// body for its header.
+fn reject_block(
+    blockchain: &Blockchain,
+    peer: &str,
+    ip: IpAddr,
+    e: &BlockchainError,
+) -> Result<(), BlockchainError> {
+    error!("Rejected block from {}: {}", peer, e);
+    if let BlockchainError::ValidationError(_) = e {
+        misbehaving(blockchain, peer, ip, INVALID_BLOCK_SCORE, e)?;
+    }
+    GLOBAL_BLOCKS_IN_TRANSIT.remove_peer(peer);
+    GLOBAL_NODES.evict_node(peer);
+    GLOBAL_BLOCKS_IN_TRANSIT.set_wanted(&blockchain.get_missing_block_hashes()?);
+    Ok(())
+}
+
+// Sends the block requests the download scheduler hands out, including
+// re-requests of blocks a stalling peer did not deliver
+fn request_blocks() {
+    for (peer, block_hash) in GLOBAL_BLOCKS_IN_TRANSIT.next_requests() {
+        let Some(node) = GLOBAL_NODES.get_node(peer.as_str()) else {
+            warn!("Peer {} to request blocks from is disconnected", peer);
+            GLOBAL_BLOCKS_IN_TRANSIT.remove_peer(peer.as_str());
+            continue;
+        };
+        // The blocks go back to the queue for the remaining peers
+        if let Err(e) = send_get_data(&node, OpType::Block, block_hash.as_slice()) {
+            warn!("Failed to request blocks from {}: {}", peer, e);
+            GLOBAL_BLOCKS_IN_TRANSIT.remove_peer(peer.as_str());
+        }
+    }
+}
+
+// Transactions of blocks that left the active chain become unconfirmed again,
+// transactions of newly connected blocks are no longer pending
+fn update_memory_pool(
+    blockchain: &Blockchain,
+    chain_update: &ChainUpdate,
+) -> Result<(), BlockchainError> {
+    for block in chain_update.get_connected() {
+        GLOBAL_MEMORY_POOL.remove_confirmed(block);
+    }
+    // Oldest first, so parents are back in the pool before their children
+    for block in chain_update.get_disconnected().iter().rev() {
+        for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+            // Transactions the new chain spent again are dropped
+            match GLOBAL_MEMORY_POOL.accept(blockchain, tx.clone()) {
+                Ok(_) => {}
+                Err(e @ BlockchainError::InvalidTransaction(_))
+                | Err(e @ BlockchainError::Rejected(_)) => {
+                    info!("Not returning a transaction to the memory pool: {}", e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_addr(addr: &str) -> Result<SocketAddr, BlockchainError> {
+    addr.parse()
+        .map_err(|_| BlockchainError::NetworkError(format!("invalid peer address {}", addr)))
+}
+
+// Connects with a timeout, retrying with a growing delay before giving up
+fn connect(addr: SocketAddr) -> Result<TcpStream, BlockchainError> {
+    let timeout = Duration::from_millis(TCP_CONNECT_TIMEOUT);
+    let mut backoff = Duration::from_millis(CONNECT_BACKOFF);
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempt == CONNECT_ATTEMPTS => {
+                return Err(BlockchainError::NetworkError(format!(
+                    "failed to connect to {} after {} attempts: {}",
+                    addr, attempt, e
+                )));
+            }
+            Err(e) => {
+                warn!("Failed to connect to {}, retrying: {}", addr, e);
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// A registered peer that cannot be written to is disconnected
+fn send_data(node: &Node, pkg: Package) -> Result<(), BlockchainError> {
     info!("send package: {:?}", &pkg);
-    let stream = TcpStream::connect(addr).unwrap_or_else(|e| {
-        error!("The {} is not valid: {}", addr, e);
-        GLOBAL_NODES.evict_node(addr.to_string().as_str());
-        panic!("Failed to connect to {}: {}", addr, e);
-    });
-    
-    let mut stream = stream;
-    let _ = stream.set_write_timeout(Option::from(Duration::from_millis(TCP_WRITE_TIMEOUT)));
-    let _ = serde_json::to_writer(&stream, &pkg);
-    let _ = stream.flush();
+    node.send(&pkg).inspect_err(|_| GLOBAL_NODES.evict_node(node.get_addr().as_str()))
+}
+
+// Submits a transaction from the command line and reports the reason when
+// the node turns it away
+pub fn submit_tx(addr: &str, tx: &Transaction) -> Result<(), BlockchainError> {
+    let (mut stream, mut reader, codec) = handshake_once(parse_addr(addr)?)?;
+    let pkg = Package::Tx {
+        addr_from: String::new(),
+        transaction: tx.serialize(),
+    };
+    codec.write_package(&mut stream, &pkg)?;
+    let _ = stream.shutdown(Shutdown::Write);
+    // The node closes the connection once it has accepted the transaction
+    loop {
+        match codec.read_package(&mut reader)? {
+            Some(Package::Reject { reason, .. }) => return Err(BlockchainError::Rejected(reason)),
+            Some(_) => {}
+            None => return Ok(()),
+        }
+    }
+}
+
+fn handshake_once(
+    addr: SocketAddr,
+) -> Result<(TcpStream, BufReader<TcpStream>, Codec), BlockchainError> {
+    let network_error = |e: std::io::Error| BlockchainError::NetworkError(e.to_string());
+    let mut stream = connect(addr)?;
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(TCP_WRITE_TIMEOUT)));
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)));
+    let codec = Codec::from_config();
+    codec.write_package(&mut stream, &client_version_package())?;
+
+    let mut reader = BufReader::new(stream.try_clone().map_err(network_error)?);
+    let mut handshake = Handshake::default();
+    while !handshake.is_established() {
+        match codec.read_package(&mut reader)? {
+            Some(Package::Version {
+                addr_from,
+                best_height,
+                ..
+            }) => {
+                codec.write_package(&mut stream, &verack_package())?;
+                handshake.peer = Some((addr_from, best_height));
+            }
+            Some(Package::VerAck { .. }) => handshake.verack_received = true,
+            Some(_) => {}
+            None => {
+                return Err(BlockchainError::NetworkError(String::from(
+                    "connection closed during the handshake",
+                )))
+            }
+        }
+    }
+    Ok((stream, reader, codec))
 }
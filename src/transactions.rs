@@ -1,11 +1,16 @@
 use crate::wallet::hash_pub_key;
-use crate::{base58_decode, wallet, Blockchain, UTXOSet, Wallets};
+use crate::{wallet, Address, BlockchainError, UTXOView, Wallets};
 use data_encoding::HEXLOWER;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 
-const SUBSIDY: i32 = 10;
+pub const SUBSIDY: i32 = 10;
+
+// An input below SEQUENCE_FINAL - 1 signals that its transaction may be
+// replaced by one paying a higher fee while it is unconfirmed
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+const SEQUENCE_REPLACEABLE: u32 = SEQUENCE_FINAL - 2;
 
 
 #[derive(Clone, Default, Serialize, Deserialize)]
@@ -14,6 +19,7 @@ pub struct TXInput {
     vout: usize,       
     signature: Vec<u8>,
     pub_key: Vec<u8>,  
+    sequence: u32,
 }
 
 impl TXInput {
@@ -24,6 +30,7 @@ impl TXInput {
             vout,
             signature: vec![],
             pub_key: vec![],
+            sequence: SEQUENCE_FINAL,
         }
     }
 
@@ -39,10 +46,14 @@ impl TXInput {
         self.pub_key.as_slice()
     }
 
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
+    }
+
 
     pub fn uses_key(&self, pub_key_hash: &[u8]) -> bool {
         let locking_hash = wallet::hash_pub_key(self.pub_key.as_slice());
-        return locking_hash.eq(pub_key_hash);
+        locking_hash.eq(pub_key_hash)
     }
 }
 
@@ -55,13 +66,13 @@ pub struct TXOutput {
 
 impl TXOutput {
    
-    pub fn new(value: i32, address: &str) -> TXOutput {
+    pub fn new(value: i32, address: &Address) -> TXOutput {
         let mut output = TXOutput {
             value,
             pub_key_hash: vec![],
         };
         output.lock(address);
-        return output;
+        output
     }
 
     pub fn get_value(&self) -> i32 {
@@ -72,10 +83,8 @@ impl TXOutput {
         self.pub_key_hash.as_slice()
     }
 
-    fn lock(&mut self, address: &str) {
-        let payload = base58_decode(address);
-        let pub_key_hash = payload[1..payload.len() - wallet::ADDRESS_CHECK_SUM_LEN].to_vec();
-        self.pub_key_hash = pub_key_hash;
+    fn lock(&mut self, address: &Address) {
+        self.pub_key_hash = address.get_pub_key_hash().to_vec();
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
@@ -84,6 +93,12 @@ impl TXOutput {
 }
 
 
+// Sum of the output values, None when it overflows
+pub(crate) fn sum_values<'a>(outs: impl IntoIterator<Item = &'a TXOutput>) -> Option<i32> {
+    outs.into_iter().try_fold(0i32, |sum, out| sum.checked_add(out.get_value()))
+}
+
+
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Transaction {
     id: Vec<u8>,         
@@ -92,11 +107,14 @@ pub struct Transaction {
 }
 
 impl Transaction {
-   
-    pub fn new_coinbase_tx(to: &str) -> Transaction {
-        let txout = TXOutput::new(SUBSIDY, to);
-        let mut tx_input = TXInput::default();
-        tx_input.signature = Uuid::new_v4().as_bytes().to_vec();
+    // Pays the subsidy and the fees of the other block transactions to `to`
+    pub fn new_coinbase_tx(to: &Address, fees: i32) -> Transaction {
+        let txout = TXOutput::new(SUBSIDY + fees, to);
+        let tx_input = TXInput {
+            signature: Uuid::new_v4().as_bytes().to_vec(),
+            sequence: SEQUENCE_FINAL,
+            ..Default::default()
+        };
 
         let mut tx = Transaction {
             id: vec![],
@@ -108,33 +126,59 @@ impl Transaction {
         tx
     }
 
+    // An unsigned transaction, letting tests build spends the wallet would
+    // refuse to
+    #[cfg(test)]
+    pub(crate) fn new_unsigned(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Transaction {
+        let mut tx = Transaction { id: vec![], vin, vout };
+        tx.id = tx.hash();
+        tx
+    }
+
    
     pub fn new_utxo_transaction(
-        from: &str,
-        to: &str,
+        from: &Address,
+        to: &Address,
         amount: i32,
-        utxo_set: &UTXOSet,
-    ) -> Transaction {
-       
-        let wallets = Wallets::new();
-        let wallet = wallets.get_wallet(from).expect("unable to found wallet");
+        fee: i32,
+        replaceable: bool,
+        view: &UTXOView,
+    ) -> Result<Transaction, BlockchainError> {
+        if fee < 0 {
+            return Err(BlockchainError::InvalidTransaction(format!("negative fee {}", fee)));
+        }
+        // The inputs cover the amount and the fee, the rest is change
+        let needed = amount.checked_add(fee).ok_or_else(|| {
+            let reason = format!("amount {} and fee {} overflow", amount, fee);
+            BlockchainError::InvalidTransaction(reason)
+        })?;
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_wallet(from)
+            .ok_or_else(|| BlockchainError::WalletError(format!("no wallet for {}", from)))?;
         let public_key_hash = hash_pub_key(wallet.get_public_key());
       
         let (accumulated, valid_outputs) =
-            utxo_set.find_spendable_outputs(public_key_hash.as_slice(), amount);
-        if accumulated < amount {
-            panic!("Error: Not enough funds")
+            view.find_spendable_outputs(public_key_hash.as_slice(), needed)?;
+        if accumulated < needed {
+            return Err(BlockchainError::InsufficientFunds(format!(
+                "{} has {} but {} are needed",
+                from, accumulated, needed
+            )));
         }
      
         let mut inputs = vec![];
         for (txid_hex, outs) in valid_outputs {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
+            let txid = HEXLOWER
+                .decode(txid_hex.as_bytes())
+                .map_err(|e| BlockchainError::CorruptData(e.to_string()))?;
             for out in outs {
                 let input = TXInput {
                     txid: txid.clone(), 
                     vout: out,          
                     signature: vec![],
                     pub_key: wallet.get_public_key().to_vec(),
+                    sequence: if replaceable { SEQUENCE_REPLACEABLE } else { SEQUENCE_FINAL },
                 };
                 inputs.push(input);
             }
@@ -142,8 +186,8 @@ impl Transaction {
     
         let mut outputs = vec![TXOutput::new(amount, to)];
  
-        if accumulated > amount {
-            outputs.push(TXOutput::new(accumulated - amount, from)) // to: 币收入
+        if accumulated > needed {
+            outputs.push(TXOutput::new(accumulated - needed, from)) // to: 币收入
         }
     
         let mut tx = Transaction {
@@ -154,8 +198,79 @@ impl Transaction {
      
         tx.id = tx.hash();
     
-        tx.sign(utxo_set.get_blockchain(), wallet.get_pkcs8());
-        return tx;
+        tx.sign(view, wallet.get_pkcs8())?;
+        Ok(tx)
+    }
+
+    // The same payment paying `fee` instead, the difference taken from the
+    // change. The outputs the original spends must be unspent in `view`.
+    pub fn new_replacement(
+        original: &Transaction,
+        fee: i32,
+        view: &UTXOView,
+    ) -> Result<Transaction, BlockchainError> {
+        let txid_hex = HEXLOWER.encode(original.get_id());
+        let invalid = |reason: String| {
+            BlockchainError::InvalidTransaction(format!("{} {}", txid_hex, reason))
+        };
+        if original.is_coinbase() || original.vin.is_empty() {
+            return Err(invalid(String::from("has no inputs to spend again")));
+        }
+        if !original.is_replaceable() {
+            return Err(invalid(String::from("was not sent as replaceable")));
+        }
+        let mut prev_outs = vec![];
+        for vin in original.get_vin() {
+            let prev_out = view.find_output(vin.get_txid(), vin.vout)?.ok_or_else(|| {
+                let prev_txid_hex = HEXLOWER.encode(vin.get_txid());
+                BlockchainError::NotFoundError(format!("output {}:{}", prev_txid_hex, vin.vout))
+            })?;
+            prev_outs.push(prev_out);
+        }
+        let overflow = || invalid(String::from("has values that overflow"));
+        let input_value = sum_values(&prev_outs).ok_or_else(overflow)?;
+        let output_value = sum_values(&original.vout).ok_or_else(overflow)?;
+        let original_fee = input_value.checked_sub(output_value).ok_or_else(overflow)?;
+        if fee <= original_fee {
+            return Err(invalid(format!(
+                "already pays a fee of {}, the new fee must be higher",
+                original_fee
+            )));
+        }
+        let increase = fee - original_fee;
+
+        // Wallet transactions spend the outputs of a single address
+        let from_hash = hash_pub_key(original.vin[0].get_pub_key());
+        let from = Address::from_pub_key_hash(from_hash.as_slice());
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_wallet(&from)
+            .ok_or_else(|| BlockchainError::WalletError(format!("no wallet for {}", from)))?;
+
+        let mut tx = original.trimmed_copy();
+        for (vin, original_vin) in tx.vin.iter_mut().zip(original.get_vin()) {
+            vin.pub_key = original_vin.pub_key.clone();
+        }
+        // The change is the last output back to the sender
+        let change = tx
+            .vout
+            .iter()
+            .rposition(|out| out.is_locked_with_key(from_hash.as_slice()))
+            .filter(|idx| tx.vout[*idx].get_value() >= increase)
+            .ok_or_else(|| {
+                BlockchainError::InsufficientFunds(format!(
+                    "{} has no change to pay {} more in fees",
+                    txid_hex, increase
+                ))
+            })?;
+        tx.vout[change].value -= increase;
+        if tx.vout[change].get_value() == 0 {
+            tx.vout.remove(change);
+        }
+
+        tx.id = tx.hash();
+        tx.sign(view, wallet.get_pkcs8())?;
+        Ok(tx)
     }
 
    
@@ -163,7 +278,8 @@ impl Transaction {
         let mut inputs = vec![];
         let mut outputs = vec![];
         for input in &self.vin {
-            let txinput = TXInput::new(input.get_txid(), input.get_vout());
+            let mut txinput = TXInput::new(input.get_txid(), input.get_vout());
+            txinput.sequence = input.sequence;
             inputs.push(txinput);
         }
         for output in &self.vout {
@@ -176,32 +292,57 @@ impl Transaction {
         }
     }
 
-    fn sign(&mut self, blockchain: &Blockchain, pkcs8: &[u8]) {
+    fn sign(&mut self, view: &UTXOView, pkcs8: &[u8]) -> Result<(), BlockchainError> {
         let mut tx_copy = self.trimmed_copy();
 
         for (idx, vin) in self.vin.iter_mut().enumerate() {
-            let prev_tx = blockchain.find_transaction(vin.get_txid()).unwrap();
+            let prev_out = view
+                .find_output(vin.get_txid(), vin.vout)?
+                .ok_or_else(|| {
+                    let txid_hex = HEXLOWER.encode(vin.get_txid());
+                    BlockchainError::NotFoundError(format!("output {}:{}", txid_hex, vin.vout))
+                })?;
             tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = prev_out.pub_key_hash;
             tx_copy.id = tx_copy.hash();
             tx_copy.vin[idx].pub_key = vec![];
 
-            let signature = crate::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id());
+            let signature = crate::ecdsa_p256_sha256_sign_digest(pkcs8, tx_copy.get_id())?;
             vin.signature = signature;
         }
+        Ok(())
     }
 
-    pub fn verify(&self, blockchain: &Blockchain) -> bool {
+    // Checks the signatures against the outputs the inputs spend in the view,
+    // so that they can be unconfirmed
+    pub fn verify(&self, view: &UTXOView) -> Result<bool, BlockchainError> {
         if self.is_coinbase() {
-            return true;
+            return Ok(true);
+        }
+
+        let mut prev_outs = vec![];
+        for vin in self.vin.iter() {
+            // An input spending an unknown or spent output cannot carry a
+            // valid signature
+            match view.find_output(vin.get_txid(), vin.vout)? {
+                Some(prev_out) => prev_outs.push(prev_out),
+                None => return Ok(false),
+            }
+        }
+        Ok(self.verify_signatures(prev_outs.as_slice()))
+    }
+
+    // Checks the input signatures against the outputs they spend, given in
+    // input order
+    pub fn verify_signatures(&self, prev_outs: &[TXOutput]) -> bool {
+        if prev_outs.len() != self.vin.len() {
+            return false;
         }
-        
         let mut tx_copy = self.trimmed_copy();
         
         for (idx, vin) in self.vin.iter().enumerate() {
-            let prev_tx = blockchain.find_transaction(vin.get_txid()).unwrap();
             tx_copy.vin[idx].signature = vec![];
-            tx_copy.vin[idx].pub_key = prev_tx.vout[vin.vout].pub_key_hash.clone();
+            tx_copy.vin[idx].pub_key = prev_outs[idx].pub_key_hash.clone();
             tx_copy.id = tx_copy.hash();
             tx_copy.vin[idx].pub_key = vec![];
 
@@ -220,12 +361,17 @@ impl Transaction {
     }
 
 
+    // Opted in to be replaced by a transaction paying a higher fee
+    pub fn is_replaceable(&self) -> bool {
+        self.vin.iter().any(|vin| vin.sequence < SEQUENCE_FINAL - 1)
+    }
+
     pub fn is_coinbase(&self) -> bool {
-        return self.vin.len() == 1 && self.vin[0].pub_key.len() == 0;
+        self.vin.len() == 1 && self.vin[0].pub_key.is_empty()
     }
 
   
-    fn hash(&mut self) -> Vec<u8> {
+    fn hash(&self) -> Vec<u8> {
         let tx_copy = Transaction {
             id: vec![],
             vin: self.vin.clone(),
@@ -234,6 +380,18 @@ impl Transaction {
         crate::sha256_digest(tx_copy.serialize().as_slice())
     }
 
+    // The id is computed before signing, except for the coinbase whose
+    // signature field carries random data
+    pub fn has_valid_id(&self) -> bool {
+        let mut tx_copy = self.clone();
+        if !self.is_coinbase() {
+            for vin in tx_copy.vin.iter_mut() {
+                vin.signature = vec![];
+            }
+        }
+        self.id.eq(&tx_copy.hash())
+    }
+
     pub fn get_id(&self) -> &[u8] {
         self.id.as_slice()
     }
@@ -254,7 +412,28 @@ impl Transaction {
         bincode::serialize(self).unwrap().to_vec()
     }
 
-    pub fn deserialize(bytes: &[u8]) -> Transaction {
-        bincode::deserialize(bytes).unwrap()
+    // Serialized size in bytes, which fee rates are measured against
+    pub fn get_size(&self) -> usize {
+        self.serialize().len()
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Transaction, BlockchainError> {
+        bincode::deserialize(bytes)
+            .map_err(|e| BlockchainError::DeserializationError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_sums_report_overflow() {
+        let address = Address::from_pub_key_hash(&[7; 20]);
+        let outs = [TXOutput::new(i32::MAX, &address), TXOutput::new(1, &address)];
+        assert_eq!(sum_values(&outs[..1]), Some(i32::MAX));
+        assert_eq!(sum_values(&outs), None);
+        let wrapping = [i32::MAX, i32::MAX, 2].map(|value| TXOutput::new(value, &address));
+        assert_eq!(sum_values(&wrapping), None);
     }
 }
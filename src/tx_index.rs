@@ -0,0 +1,100 @@
+use crate::block::Block;
+use crate::utxo_set::abort;
+use crate::{Blockchain, BlockchainError, Transaction};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+
+pub const TX_INDEX_TREE: &str = "txindex";
+// Marks the index as complete for the active chain, txids never collide with it
+const ENABLED_KEY: &str = "enabled";
+
+/// Where a transaction of the active chain is stored ( K -> txid, V -> TxLocation )
+#[derive(Serialize, Deserialize)]
+pub struct TxLocation {
+    block_hash: String,
+    position: usize,
+}
+
+pub struct TxIndex {
+    blockchain: Blockchain,
+}
+
+impl TxIndex {
+    pub fn new(blockchain: Blockchain) -> TxIndex {
+        TxIndex { blockchain }
+    }
+
+    pub fn is_enabled(&self) -> Result<bool, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let index_tree = db.open_tree(TX_INDEX_TREE)?;
+        Ok(index_tree.contains_key(ENABLED_KEY)?)
+    }
+
+    pub fn find_transaction(&self, txid: &[u8]) -> Result<Option<Transaction>, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let index_tree = db.open_tree(TX_INDEX_TREE)?;
+        let location_bytes = match index_tree.get(txid)? {
+            Some(location_bytes) => location_bytes,
+            None => return Ok(None),
+        };
+        let location: TxLocation = bincode::deserialize(location_bytes.as_ref())?;
+        let block = self.blockchain.get_block(location.block_hash.as_bytes())?;
+        Ok(block.and_then(|block| block.get_transactions().get(location.position).cloned()))
+    }
+
+    // Builds the index from the active chain and keeps it maintained from then on.
+    // Returns the number of indexed transactions.
+    pub fn reindex(&self) -> Result<usize, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let index_tree = db.open_tree(TX_INDEX_TREE)?;
+        index_tree.clear()?;
+
+        let mut counter = 0;
+        for block in self.blockchain.iterator() {
+            let block = block?;
+            for (position, tx) in block.get_transactions().iter().enumerate() {
+                let location = TxLocation {
+                    block_hash: String::from(block.get_hash()),
+                    position,
+                };
+                let location_bytes = bincode::serialize(&location)?;
+                index_tree.insert(tx.get_id(), location_bytes)?;
+                counter += 1;
+            }
+        }
+        index_tree.insert(ENABLED_KEY, vec![])?;
+        Ok(counter)
+    }
+
+    // Adds the block transactions when the index is enabled, inside the
+    // transaction that connects the block
+    pub(crate) fn index_block(
+        index_tree: &TransactionalTree,
+        block: &Block,
+    ) -> ConflictableTransactionResult<(), BlockchainError> {
+        if index_tree.get(ENABLED_KEY)?.is_none() {
+            return Ok(());
+        }
+        for (position, tx) in block.get_transactions().iter().enumerate() {
+            let location = TxLocation {
+                block_hash: String::from(block.get_hash()),
+                position,
+            };
+            index_tree.insert(tx.get_id(), bincode::serialize(&location).map_err(abort)?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unindex_block(
+        index_tree: &TransactionalTree,
+        block: &Block,
+    ) -> ConflictableTransactionResult<(), BlockchainError> {
+        if index_tree.get(ENABLED_KEY)?.is_none() {
+            return Ok(());
+        }
+        for tx in block.get_transactions() {
+            index_tree.remove(tx.get_id())?;
+        }
+        Ok(())
+    }
+}
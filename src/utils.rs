@@ -1,8 +1,8 @@
+use crate::BlockchainError;
 use crypto::digest::Digest;
 use ring::digest::{Context, SHA256};
 use ring::rand::SystemRandom;
 use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED, ECDSA_P256_SHA256_FIXED_SIGNING};
-use std::iter::repeat;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 
@@ -25,9 +25,9 @@ pub fn sha256_digest(data: &[u8]) -> Vec<u8> {
 pub fn ripemd160_digest(data: &[u8]) -> Vec<u8> {
     let mut ripemd160 = crypto::ripemd160::Ripemd160::new();
     ripemd160.input(data);
-    let mut buf: Vec<u8> = repeat(0).take(ripemd160.output_bytes()).collect();
+    let mut buf: Vec<u8> = vec![0; ripemd160.output_bytes()];
     ripemd160.result(&mut buf);
-    return buf;
+    buf
 }
 
 
@@ -36,22 +36,30 @@ pub fn base58_encode(data: &[u8]) -> String {
 }
 
 
-pub fn base58_decode(data: &str) -> Vec<u8> {
-    bs58::decode(data).into_vec().unwrap()
+pub fn base58_decode(data: &str) -> Result<Vec<u8>, bs58::decode::Error> {
+    bs58::decode(data).into_vec()
 }
 
 
-pub fn new_key_pair() -> Vec<u8> {
+pub fn new_key_pair() -> Result<Vec<u8>, BlockchainError> {
     let rng = SystemRandom::new();
-    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
-    pkcs8.as_ref().to_vec()
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+        .map_err(|_| BlockchainError::WalletError(String::from("failed to generate a key pair")))?;
+    Ok(pkcs8.as_ref().to_vec())
 }
 
 
-pub fn ecdsa_p256_sha256_sign_digest(pkcs8: &[u8], message: &[u8]) -> Vec<u8> {
-    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8).unwrap();
+pub fn ecdsa_p256_sha256_sign_digest(
+    pkcs8: &[u8],
+    message: &[u8],
+) -> Result<Vec<u8>, BlockchainError> {
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+        .map_err(|e| BlockchainError::CorruptData(format!("invalid private key: {}", e)))?;
     let rng = ring::rand::SystemRandom::new();
-    key_pair.sign(&rng, message).unwrap().as_ref().to_vec()
+    let signature = key_pair
+        .sign(&rng, message)
+        .map_err(|_| BlockchainError::WalletError(String::from("failed to sign")))?;
+    Ok(signature.as_ref().to_vec())
 }
 
 
@@ -1,10 +1,33 @@
-use crate::transactions::TXOutput;
+use crate::transactions::{sum_values, TXOutput, Transaction};
 use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::BlockchainError;
 use data_encoding::HEXLOWER;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionResult,
+    TransactionalTree,
+};
+use sled::Transactional;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-const UTXO_TREE: &str = "chainstate";
+pub const UTXO_TREE: &str = "chainstate";
+pub const UNDO_TREE: &str = "undo";
+
+/// Unspent outputs of a single transaction ( K -> vout, V -> TXOutput )
+type UnspentOutputs = BTreeMap<usize, TXOutput>;
+
+// ( txid, vout )
+pub(crate) type OutPoint = (Vec<u8>, usize);
+
+/// An output spent by a block, kept so the block can be disconnected again
+/// ( K -> block hash, V -> Vec<SpentOutput> in spending order )
+#[derive(Serialize, Deserialize)]
+struct SpentOutput {
+    txid: Vec<u8>,
+    vout: usize,
+    output: TXOutput,
+}
 
 
 pub struct UTXOSet {
@@ -22,122 +45,291 @@ impl UTXOSet {
     }
 
 
-    pub fn find_spendable_outputs(
-        &self,
-        pub_key_hash: &[u8],
-        amount: i32,
-    ) -> (i32, HashMap<String, Vec<usize>>) {
-        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
-        let mut accumulated = 0;
+  
+    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>, BlockchainError> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        
+        let mut utxos = vec![];
         
         for item in utxo_tree.iter() {
-            let (k, v) = item.unwrap();
-            
-            let txid_hex = HEXLOWER.encode(k.to_vec().as_slice());
+            let (_, v) = item?;
             
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice()).unwrap();
+            let outs: UnspentOutputs = bincode::deserialize(v.to_vec().as_slice())?;
             
-            for (idx, out) in outs.iter().enumerate() {
-                if out.is_locked_with_key(pub_key_hash) && accumulated < amount {
-                    accumulated += out.get_value();
-                    if unspent_outputs.contains_key(txid_hex.as_str()) {
-                        unspent_outputs.get_mut(txid_hex.as_str()).unwrap().push(idx);
-                    } else {
-                        unspent_outputs.insert(txid_hex.clone(), vec![idx]);
-                    }
+            for out in outs.values() {
+                if out.is_locked_with_key(pub_key_hash) {
+                    utxos.push(out.clone())
                 }
             }
         }
         
-        (accumulated, unspent_outputs)
+        Ok(utxos)
     }
 
-  
-    pub fn find_utxo(&self, pub_key_hash: &[u8]) -> Vec<TXOutput> {
+    pub fn find_output(
+        &self,
+        txid: &[u8],
+        vout: usize,
+    ) -> Result<Option<TXOutput>, BlockchainError> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        
-        let mut utxos = vec![];
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
         
-        for item in utxo_tree.iter() {
-            let (_, v) = item.unwrap();
-            
-            let outs: Vec<TXOutput> = bincode::deserialize(v.to_vec().as_slice()).unwrap();
-            
-            for out in outs.iter() {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone())
-                }
+        let outs_bytes = match utxo_tree.get(txid)? {
+            Some(outs_bytes) => outs_bytes,
+            None => return Ok(None),
+        };
+        let mut outs: UnspentOutputs = bincode::deserialize(outs_bytes.as_ref())?;
+        Ok(outs.remove(&vout))
+    }
+
+    // Inputs minus outputs, or None when an input is not in the set. Values
+    // that overflow are a ValidationError.
+    pub fn get_fee(&self, tx: &Transaction) -> Result<Option<i32>, BlockchainError> {
+        let mut prev_outs = vec![];
+        for vin in tx.get_vin() {
+            match self.find_output(vin.get_txid(), vin.get_vout())? {
+                Some(out) => prev_outs.push(out),
+                None => return Ok(None),
             }
         }
-        
-        utxos
+        let overflow = || {
+            let txid_hex = HEXLOWER.encode(tx.get_id());
+            BlockchainError::ValidationError(format!("transaction {} values overflow", txid_hex))
+        };
+        let input_value = sum_values(&prev_outs).ok_or_else(overflow)?;
+        let output_value = sum_values(tx.get_vout()).ok_or_else(overflow)?;
+        input_value.checked_sub(output_value).map(Some).ok_or_else(overflow)
     }
 
    
-    pub fn count_transactions(&self) -> i32 {
+    pub fn count_transactions(&self) -> Result<i32, BlockchainError> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
         
         let mut counter = 0;
         for _ in utxo_tree.iter() {
             counter += 1;
         }
         
-        counter
+        Ok(counter)
     }
 
-    pub fn reindex(&self) {
+    // Rebuilds the UTXO set and the undo records by replaying the active chain
+    pub fn reindex(&self) -> Result<(), BlockchainError> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
-        let _ = utxo_tree.clear().unwrap();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        utxo_tree.clear()?;
+        let undo_tree = db.open_tree(UNDO_TREE)?;
+        undo_tree.clear()?;
 
-        let utxo_map = self.blockchain.find_utxo();
-        
-        for (txid_hex, outs) in &utxo_map {
-            let txid = HEXLOWER.decode(txid_hex.as_bytes()).unwrap();
-            let value = bincode::serialize(outs).unwrap();
-            let _ = utxo_tree.insert(txid.as_slice(), value).unwrap();
+        for block in self.blockchain.forward_iterator() {
+            self.update(&block?)?;
         }
+        Ok(())
     }
 
   
-    pub fn update(&self, block: &Block) {
+    pub fn update(&self, block: &Block) -> Result<(), BlockchainError> {
         let db = self.blockchain.get_db();
-        let utxo_tree = db.open_tree(UTXO_TREE).unwrap();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let undo_tree = db.open_tree(UNDO_TREE)?;
+        let result: TransactionResult<(), BlockchainError> = (&utxo_tree, &undo_tree)
+            .transaction(|(utxo_tree, undo_tree)| Self::apply_block(utxo_tree, undo_tree, block));
+        Ok(result?)
+    }
+
+    // Reverts `update` for the tip block using its undo record
+    pub fn disconnect(&self, block: &Block) -> Result<(), BlockchainError> {
+        let db = self.blockchain.get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+        let undo_tree = db.open_tree(UNDO_TREE)?;
+        let result: TransactionResult<(), BlockchainError> = (&utxo_tree, &undo_tree)
+            .transaction(|(utxo_tree, undo_tree)| Self::revert_block(utxo_tree, undo_tree, block));
+        Ok(result?)
+    }
+
+    // The tip block is connected together with its undo record, so a missing
+    // record means the UTXO set was not built for the current chain
+    pub fn is_consistent(&self) -> Result<bool, BlockchainError> {
+        let db = self.blockchain.get_db();
+        let undo_tree = db.open_tree(UNDO_TREE)?;
+        Ok(undo_tree.contains_key(self.blockchain.get_tip_hash())?)
+    }
+
+    // Spends the block inputs, adds its outputs and records what was spent,
+    // inside a transaction spanning the chainstate and undo trees
+    pub(crate) fn apply_block(
+        utxo_tree: &TransactionalTree,
+        undo_tree: &TransactionalTree,
+        block: &Block,
+    ) -> ConflictableTransactionResult<(), BlockchainError> {
+        let mut spent_outputs = vec![];
         
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in tx.get_vin() {
-                    let mut updated_outs = vec![];
-                    let outs_bytes = utxo_tree.get(vin.get_txid()).unwrap().unwrap();
+                    let missing_output = || {
+                        let txid_hex = HEXLOWER.encode(vin.get_txid());
+                        let outpoint = format!("output {}:{}", txid_hex, vin.get_vout());
+                        let err = BlockchainError::NotFoundError(outpoint);
+                        ConflictableTransactionError::Abort(err)
+                    };
+                    let outs_bytes = utxo_tree.get(vin.get_txid())?.ok_or_else(missing_output)?;
                     
-                    let outs: Vec<TXOutput> = bincode::deserialize(outs_bytes.as_ref()).unwrap();
-                    
-                    for (idx, out) in outs.iter().enumerate() {
-                        if idx != vin.get_vout() {
-                            updated_outs.push(out.clone())
-                        }
-                    }
+                    let mut updated_outs: UnspentOutputs =
+                        bincode::deserialize(outs_bytes.as_ref()).map_err(abort)?;
+                    let output = updated_outs.remove(&vin.get_vout()).ok_or_else(missing_output)?;
+                    spent_outputs.push(SpentOutput {
+                        txid: vin.get_txid().to_vec(),
+                        vout: vin.get_vout(),
+                        output,
+                    });
                     
                     if updated_outs.is_empty() {
-                        let _ = utxo_tree.remove(vin.get_txid()).unwrap();
+                        utxo_tree.remove(vin.get_txid())?;
                     } else {
-                        let outs_bytes = bincode::serialize(&updated_outs).unwrap();
-                        let _ = utxo_tree.insert(vin.get_txid(), outs_bytes).unwrap();
+                        let outs_bytes = bincode::serialize(&updated_outs).map_err(abort)?;
+                        utxo_tree.insert(vin.get_txid(), outs_bytes)?;
                     }
                 }
             }
             
-            let mut new_outputs = vec![];
-            for out in tx.get_vout() {
-                new_outputs.push(out.clone())
+            let mut new_outputs = UnspentOutputs::new();
+            for (idx, out) in tx.get_vout().iter().enumerate() {
+                new_outputs.insert(idx, out.clone());
             }
             
-            let outs_bytes = bincode::serialize(&new_outputs).unwrap();
-            let _ = utxo_tree.insert(tx.get_id(), outs_bytes).unwrap();
+            let outs_bytes = bincode::serialize(&new_outputs).map_err(abort)?;
+            utxo_tree.insert(tx.get_id(), outs_bytes)?;
         }
+
+        let undo_bytes = bincode::serialize(&spent_outputs).map_err(abort)?;
+        undo_tree.insert(block.get_hash(), undo_bytes)?;
+        Ok(())
     }
+
+    // Drops the outputs the block created and restores the outputs it spent
+    pub(crate) fn revert_block(
+        utxo_tree: &TransactionalTree,
+        undo_tree: &TransactionalTree,
+        block: &Block,
+    ) -> ConflictableTransactionResult<(), BlockchainError> {
+        let undo_bytes = undo_tree.get(block.get_hash())?.ok_or_else(|| {
+            let reason = format!("no undo record for block {}", block.get_hash());
+            ConflictableTransactionError::Abort(BlockchainError::NotFoundError(reason))
+        })?;
+        let spent_outputs: Vec<SpentOutput> =
+            bincode::deserialize(undo_bytes.as_ref()).map_err(abort)?;
+
+        for spent in spent_outputs.into_iter().rev() {
+            let mut outs: UnspentOutputs = match utxo_tree.get(spent.txid.as_slice())? {
+                Some(outs_bytes) => bincode::deserialize(outs_bytes.as_ref()).map_err(abort)?,
+                None => UnspentOutputs::new(),
+            };
+            outs.insert(spent.vout, spent.output);
+            let outs_bytes = bincode::serialize(&outs).map_err(abort)?;
+            utxo_tree.insert(spent.txid.as_slice(), outs_bytes)?;
+        }
+        // Last, as restoring an output a later transaction of the block spent
+        // brings back its transaction
+        for tx in block.get_transactions() {
+            utxo_tree.remove(tx.get_id())?;
+        }
+        undo_tree.remove(block.get_hash())?;
+        Ok(())
+    }
+}
+
+/// The UTXO set with unconfirmed transactions on top: their outputs can be
+/// spent and the outputs they spend cannot
+pub struct UTXOView {
+    utxo_set: UTXOSet,
+    outputs: HashMap<OutPoint, TXOutput>, // created by unconfirmed transactions
+    spent: HashSet<OutPoint>,             // spent by unconfirmed transactions
+}
+
+impl UTXOView {
+    pub fn new(utxo_set: UTXOSet) -> UTXOView {
+        UTXOView {
+            utxo_set,
+            outputs: HashMap::new(),
+            spent: HashSet::new(),
+        }
+    }
+
+    // Spends the transaction inputs and adds its outputs, in any order
+    pub fn apply(&mut self, tx: &Transaction) {
+        if !tx.is_coinbase() {
+            for vin in tx.get_vin() {
+                self.spent.insert((vin.get_txid().to_vec(), vin.get_vout()));
+            }
+        }
+        for (idx, out) in tx.get_vout().iter().enumerate() {
+            self.outputs.insert((tx.get_id_bytes(), idx), out.clone());
+        }
+    }
+
+    pub fn find_output(
+        &self,
+        txid: &[u8],
+        vout: usize,
+    ) -> Result<Option<TXOutput>, BlockchainError> {
+        let outpoint = (txid.to_vec(), vout);
+        if self.spent.contains(&outpoint) {
+            return Ok(None);
+        }
+        if let Some(out) = self.outputs.get(&outpoint) {
+            return Ok(Some(out.clone()));
+        }
+        self.utxo_set.find_output(txid, vout)
+    }
+
+    // Outputs locked with the key until they add up to `amount`, confirmed
+    // ones first
+    pub fn find_spendable_outputs(
+        &self,
+        pub_key_hash: &[u8],
+        amount: i32,
+    ) -> Result<(i32, HashMap<String, Vec<usize>>), BlockchainError> {
+        let mut unspent_outputs: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut accumulated = 0;
+        let db = self.utxo_set.get_blockchain().get_db();
+        let utxo_tree = db.open_tree(UTXO_TREE)?;
+
+        let mut candidates: Vec<(OutPoint, i32)> = vec![];
+        for item in utxo_tree.iter() {
+            let (k, v) = item?;
+            let outs: UnspentOutputs = bincode::deserialize(v.to_vec().as_slice())?;
+            for (idx, out) in outs.iter().filter(|(_, out)| out.is_locked_with_key(pub_key_hash)) {
+                candidates.push(((k.to_vec(), *idx), out.get_value()));
+            }
+        }
+        let mut unconfirmed: Vec<(OutPoint, i32)> = self
+            .outputs
+            .iter()
+            .filter(|(_, out)| out.is_locked_with_key(pub_key_hash))
+            .map(|(outpoint, out)| (outpoint.clone(), out.get_value()))
+            .collect();
+        unconfirmed.sort();
+        candidates.extend(unconfirmed);
+
+        for ((txid, idx), value) in candidates {
+            if accumulated >= amount {
+                break;
+            }
+            if self.spent.contains(&(txid.clone(), idx)) {
+                continue;
+            }
+            accumulated += value;
+            let txid_hex = HEXLOWER.encode(txid.as_slice());
+            unspent_outputs.entry(txid_hex).or_default().push(idx);
+        }
+        Ok((accumulated, unspent_outputs))
+    }
+}
+
+// Aborts a sled transaction on data that does not encode or decode
+pub(crate) fn abort(err: bincode::Error) -> ConflictableTransactionError<BlockchainError> {
+    ConflictableTransactionError::Abort(err.into())
 }
@@ -1,10 +1,8 @@
 // wallet 
+use crate::{Address, BlockchainError};
 use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
 
-const VERSION: u8 = 0x00;
-pub const ADDRESS_CHECK_SUM_LEN: usize = 4;
-
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Wallet {
     pkcs8: Vec<u8>,
@@ -13,24 +11,18 @@ pub struct Wallet {
 
 impl Wallet {
     
-    pub fn new() -> Wallet {
-        let pkcs8 = crate::new_key_pair();
-        let key_pair =
-            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+    pub fn new() -> Result<Wallet, BlockchainError> {
+        let pkcs8 = crate::new_key_pair()?;
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref())
+            .map_err(|e| BlockchainError::WalletError(e.to_string()))?;
         let public_key = key_pair.public_key().as_ref().to_vec();
-        Wallet { pkcs8, public_key }
+        Ok(Wallet { pkcs8, public_key })
     }
 
    
-    pub fn get_address(&self) -> String {
+    pub fn get_address(&self) -> Address {
         let pub_key_hash = hash_pub_key(self.public_key.as_slice());
-        let mut payload: Vec<u8> = vec![];
-        payload.push(VERSION);
-        payload.extend(pub_key_hash.as_slice());
-        let checksum = checksum(payload.as_slice());
-        payload.extend(checksum.as_slice());
-        // version + pub_key_hash + checksum
-        crate::base58_encode(payload.as_slice())
+        Address::from_pub_key_hash(pub_key_hash.as_slice())
     }
 
     pub fn get_public_key(&self) -> &[u8] {
@@ -48,33 +40,3 @@ pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
     crate::ripemd160_digest(pub_key_sha256.as_slice())
 }
 
-
-fn checksum(payload: &[u8]) -> Vec<u8> {
-    let first_sha = crate::sha256_digest(payload);
-    let second_sha = crate::sha256_digest(first_sha.as_slice());
-    second_sha[0..ADDRESS_CHECK_SUM_LEN].to_vec()
-}
-
-
-pub fn validate_address(address: &str) -> bool {
-    let payload = crate::base58_decode(address);
-    let actual_checksum = payload[payload.len() - ADDRESS_CHECK_SUM_LEN..].to_vec();
-    let version = payload[0];
-    let pub_key_hash = payload[1..payload.len() - ADDRESS_CHECK_SUM_LEN].to_vec();
-
-    let mut target_vec = vec![];
-    target_vec.push(version);
-    target_vec.extend(pub_key_hash);
-    let target_checksum = checksum(target_vec.as_slice());
-    actual_checksum.eq(target_checksum.as_slice())
-}
-
-
-pub fn convert_address(pub_hash_key: &[u8]) -> String {
-    let mut payload: Vec<u8> = vec![];
-    payload.push(VERSION);
-    payload.extend(pub_hash_key);
-    let checksum = checksum(payload.as_slice());
-    payload.extend(checksum.as_slice());
-    crate::base58_encode(payload.as_slice())
-}
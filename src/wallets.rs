@@ -4,6 +4,7 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Write};
 
 use crate::wallet::Wallet;
+use crate::{Address, BlockchainError};
 
 pub const WALLET_FILE: &str = "wallet.dat";
 
@@ -12,67 +13,70 @@ pub struct Wallets {
 }
 
 impl Wallets {
-    pub fn new() -> Wallets {
+    pub fn new() -> Result<Wallets, BlockchainError> {
         let mut wallets = Wallets {
             wallets: HashMap::new(),
         };
-        wallets.load_from_file();
-        wallets
+        wallets.load_from_file()?;
+        Ok(wallets)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    pub fn create_wallet(&mut self) -> Result<Address, BlockchainError> {
+        let wallet = Wallet::new()?;
         let address = wallet.get_address();
-        self.wallets.insert(address.clone(), wallet);
-        self.save_to_file();
-        address
+        self.wallets.insert(address.to_string(), wallet);
+        self.save_to_file()?;
+        Ok(address)
     }
 
     pub fn get_addresses(&self) -> Vec<String> {
         let mut addresses = vec![];
-        for (address, _) in &self.wallets {
+        for address in self.wallets.keys() {
             addresses.push(address.clone())
         }
         addresses
     }
 
-    pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        if let Some(wallet) = self.wallets.get(address) {
+    pub fn get_wallet(&self, address: &Address) -> Option<&Wallet> {
+        if let Some(wallet) = self.wallets.get(&address.to_string()) {
             return Some(wallet);
         }
         None
     }
 
-    pub fn load_from_file(&mut self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+    pub fn load_from_file(&mut self) -> Result<(), BlockchainError> {
+        let path = current_dir().map_err(wallet_error)?.join(WALLET_FILE);
         if !path.exists() {
-            return;
+            return Ok(());
         }
         
-        let mut file = File::open(path).unwrap();
-        let metadata = file.metadata().unwrap();
+        let mut file = File::open(path).map_err(wallet_error)?;
+        let mut buf = vec![];
+        file.read_to_end(&mut buf).map_err(wallet_error)?;
         
-        let mut buf = vec![0; metadata.len() as usize];
-        let _ = file.read(&mut buf).unwrap();
-        
-        let wallets = bincode::deserialize(&buf[..]).unwrap();
+        let wallets = bincode::deserialize(&buf[..])?;
         self.wallets = wallets;
+        Ok(())
     }
 
-    fn save_to_file(&self) {
-        let path = current_dir().unwrap().join(WALLET_FILE);
+    #[allow(clippy::suspicious_open_options)]
+    fn save_to_file(&self) -> Result<(), BlockchainError> {
+        let path = current_dir().map_err(wallet_error)?.join(WALLET_FILE);
         
         let file = OpenOptions::new()
             .create(true)
             .write(true)
             .open(&path)
-            .unwrap();
+            .map_err(wallet_error)?;
         
         let mut writer = BufWriter::new(file);
-        let wallets_bytes = bincode::serialize(&self.wallets).unwrap();
+        let wallets_bytes = bincode::serialize(&self.wallets)?;
         
-        writer.write(wallets_bytes.as_slice()).unwrap();
-        let _ = writer.flush();
+        writer.write_all(wallets_bytes.as_slice()).map_err(wallet_error)?;
+        writer.flush().map_err(wallet_error)
     }
 }
 
+fn wallet_error(err: std::io::Error) -> BlockchainError {
+    BlockchainError::WalletError(format!("{}: {}", WALLET_FILE, err))
+}
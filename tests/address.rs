@@ -0,0 +1,40 @@
+use rust_blockchain::utils::{base58_decode, base58_encode, sha256_digest};
+use rust_blockchain::{Address, AddressError};
+
+fn sample() -> Address {
+    Address::from_pub_key_hash(&[7; 20])
+}
+
+// Re-encodes a payload with a checksum that matches it
+fn with_checksum(mut body: Vec<u8>) -> String {
+    let checksum = sha256_digest(&sha256_digest(&body));
+    body.extend(&checksum[..4]);
+    base58_encode(&body)
+}
+
+#[test]
+fn address_round_trips_through_its_string_form() {
+    let address = sample();
+    let parsed: Address = address.to_string().parse().unwrap();
+    assert_eq!(parsed, address);
+    assert_eq!(parsed.get_pub_key_hash(), &[7; 20]);
+}
+
+#[test]
+fn malformed_addresses_are_rejected_with_the_reason() {
+    assert!(matches!("".parse::<Address>(), Err(AddressError::InvalidLength(0))));
+    assert!(matches!("0OIl".parse::<Address>(), Err(AddressError::InvalidBase58(_))));
+    assert!(matches!("1111".parse::<Address>(), Err(AddressError::InvalidLength(4))));
+
+    let mut payload = base58_decode(&sample().to_string()).unwrap();
+    payload.truncate(21);
+    let mut body = payload.clone();
+    body[0] = 0x05;
+    let unknown_version = with_checksum(body);
+    assert_eq!(unknown_version.parse::<Address>(), Err(AddressError::UnknownVersion(0x05)));
+
+    payload[20] ^= 0xff;
+    payload.extend([0; 4]);
+    let corrupted = base58_encode(&payload);
+    assert_eq!(corrupted.parse::<Address>(), Err(AddressError::ChecksumMismatch));
+}
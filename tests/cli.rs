@@ -0,0 +1,187 @@
+mod common;
+
+use common::{balance, create_wallet, run, tip_hash, work_dir};
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn rollback_restores_balances_of_the_new_tip() {
+    let dir = work_dir("rollback");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let send = [
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "3", "--mine",
+    ];
+    run(&dir, &send);
+    let first_hash = tip_hash(&dir);
+    run(&dir, &send);
+    assert_eq!(balance(&dir, receiver.as_str()), 6);
+    assert_eq!(balance(&dir, miner.as_str()), 24);
+
+    let stdout = run(&dir, &["rollback", "--to-height", "1"]);
+    assert!(stdout.contains("The tip is now at height 1"), "{}", stdout);
+    assert_eq!(tip_hash(&dir), first_hash);
+    assert_eq!(balance(&dir, receiver.as_str()), 3);
+    assert_eq!(balance(&dir, miner.as_str()), 17);
+
+    // The UTXO set left by the rollback matches one rebuilt from scratch.
+    run(&dir, &["reindexutxo"]);
+    assert_eq!(balance(&dir, receiver.as_str()), 3);
+    assert_eq!(balance(&dir, miner.as_str()), 17);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn txindex_follows_connected_and_disconnected_blocks() {
+    let dir = work_dir("txindex");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let stdout = run(&dir, &["reindex", "--txindex"]);
+    assert!(stdout.contains("1 transactions in the transaction index"), "{}", stdout);
+
+    let send = [
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "4", "--mine",
+    ];
+    run(&dir, &send);
+    run(&dir, &send);
+    assert_eq!(balance(&dir, receiver.as_str()), 8);
+
+    run(&dir, &["rollback", "--to-height", "1"]);
+    run(&dir, &send);
+    assert_eq!(balance(&dir, receiver.as_str()), 8);
+
+    let stdout = run(&dir, &["reindex", "--txindex"]);
+    assert!(stdout.contains("5 transactions in the transaction index"), "{}", stdout);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn send_reports_an_unreachable_node_without_panicking() {
+    let dir = work_dir("unreachable");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+        .current_dir(&dir)
+        .args(["send", "--from", miner.as_str(), "--to", receiver.as_str()])
+        .args(["--amount", "1", "--connect", "127.0.0.1:2009"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains("ERROR: Network error: failed to connect"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn errors_are_reported_without_panicking() {
+    let dir = work_dir("errors");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+
+    let fail = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+            .current_dir(&dir)
+            .args(args)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        stderr
+    };
+
+    let stderr = fail(&["getbalance", miner.as_str()]);
+    assert!(stderr.contains("no blockchain found"), "{}", stderr);
+
+    run(&dir, &["createblockchain", miner.as_str()]);
+    let stderr = fail(&[
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "11", "--mine",
+    ]);
+    assert!(stderr.contains("ERROR: Insufficient funds"), "{}", stderr);
+    let stderr = fail(&[
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "2147483647",
+        "--mine",
+    ]);
+    assert!(stderr.contains("amount 2147483647 and fee 1 overflow"), "{}", stderr);
+
+    let stderr = fail(&["getbalance", "not-an-address"]);
+    assert!(stderr.contains("ERROR: Invalid address: invalid base58"), "{}", stderr);
+    let mut typo = miner.clone();
+    let last = if typo.pop() == Some('2') { '3' } else { '2' };
+    typo.push(last);
+    let stderr = fail(&[
+        "send", "--from", miner.as_str(), "--to", typo.as_str(), "--amount", "1", "--mine",
+    ]);
+    assert!(stderr.contains("ERROR: Invalid address: checksum mismatch"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn send_pays_its_fee_to_the_miner() {
+    let dir = work_dir("fee");
+    let miner = create_wallet(&dir);
+    let receiver = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let send = [
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "3", "--fee",
+        "2", "--mine",
+    ];
+    run(&dir, &send);
+    // The coinbase of the block claims the subsidy and the fee.
+    assert!(run(&dir, &["printchain"]).contains("-- Output value = 12"));
+    assert_eq!(balance(&dir, receiver.as_str()), 3);
+    assert_eq!(balance(&dir, miner.as_str()), 17);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+        .current_dir(&dir)
+        .args(["send", "--from", miner.as_str(), "--to", receiver.as_str()])
+        .args(["--amount", "9", "--fee", "9", "--mine"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("has 17 but 18 are needed"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn bad_configuration_is_reported_at_startup() {
+    let dir = work_dir("config");
+    let fail = |key: &str, value: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+            .current_dir(&dir)
+            .env(key, value)
+            .arg("listaddresses")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        assert_eq!(output.status.code(), Some(1), "{}", stderr);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        stderr
+    };
+
+    let stderr = fail("TARGET_BLOCK_SPACING", "0");
+    assert!(stderr.contains("ERROR: Configuration error: TARGET_BLOCK_SPACING"), "{}", stderr);
+    let stderr = fail("BAN_TIME", "a day");
+    assert!(stderr.contains("BAN_TIME must be a number of seconds, not a day"), "{}", stderr);
+    let stderr = fail("MIN_RELAY_FEE", "-1");
+    assert!(stderr.contains("MIN_RELAY_FEE must be a fee per 1000 bytes"), "{}", stderr);
+    let stderr = fail("NETWORK", "regtest");
+    assert!(stderr.contains("NETWORK must be mainnet or testnet, not regtest"), "{}", stderr);
+    let stderr = fail("WIRE_CODEC", "xml");
+    assert!(stderr.contains("WIRE_CODEC must be binary or json, not xml"), "{}", stderr);
+
+    let _ = fs::remove_dir_all(dir);
+}
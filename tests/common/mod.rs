@@ -0,0 +1,167 @@
+//! Helpers shared by the integration tests, which drive the compiled binary.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const CENTRAL_ADDR: &str = "127.0.0.1:2001";
+pub const PEER_ADDR: &str = "127.0.0.1:2002";
+const SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Every node test binds the central node address, so they cannot run concurrently.
+pub static NETWORK: Mutex<()> = Mutex::new(());
+
+/// Kills the node process when the test ends, even on panic.
+pub struct NodeProcess {
+    child: Child,
+    logs: Option<mpsc::Receiver<String>>, // log lines, read from the first wait on
+}
+
+impl Drop for NodeProcess {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rust_blockchain_{}_{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn run(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// Runs a command that must fail with an error rather than a panic, returns
+// what it printed on stderr
+pub fn run_err(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    assert_eq!(output.status.code(), Some(1), "{:?}: {}", args, stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    stderr
+}
+
+pub fn create_wallet(dir: &Path) -> String {
+    let stdout = run(dir, &["createwallet"]);
+    stdout
+        .trim()
+        .strip_prefix("Your new address: ")
+        .unwrap()
+        .to_string()
+}
+
+pub fn tip_hash(dir: &Path) -> String {
+    let stdout = run(dir, &["printchain"]);
+    stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Cur block hash: "))
+        .unwrap()
+        .to_string()
+}
+
+pub fn balance(dir: &Path, address: &str) -> i32 {
+    let stdout = run(dir, &["getbalance", address]);
+    stdout.trim().rsplit(": ").next().unwrap().parse().unwrap()
+}
+
+pub fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+pub fn start_node(dir: &Path, addr: &str, args: &[&str]) -> NodeProcess {
+    start_node_with_env(dir, addr, &[], args)
+}
+
+pub fn start_node_with_env(
+    dir: &Path,
+    addr: &str,
+    envs: &[(&str, &str)],
+    args: &[&str],
+) -> NodeProcess {
+    let child = Command::new(env!("CARGO_BIN_EXE_rust_blockchain"))
+        .current_dir(dir)
+        .env("NODE_ADDRESS", addr)
+        .envs(envs.iter().copied())
+        .arg("startnode")
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    NodeProcess { child, logs: None }
+}
+
+pub fn wait_for_listener(addr: &str) {
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(start.elapsed() < SYNC_TIMEOUT, "node {} never started", addr);
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub fn wait_for_log(node: &mut NodeProcess, expected: &str) {
+    wait_for_logs(node, &[expected]);
+}
+
+// Waits until the node logged every expected line, in any order. Later waits
+// only see what the node logged after the previous one.
+pub fn wait_for_logs(node: &mut NodeProcess, expected: &[&str]) {
+    let receiver = node.logs.get_or_insert_with(|| {
+        let (sender, receiver) = mpsc::channel();
+        let logs = node.child.stderr.take().unwrap();
+        thread::spawn(move || {
+            for line in BufReader::new(logs).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    });
+
+    let start = Instant::now();
+    let mut missing: Vec<&str> = expected.to_vec();
+    while !missing.is_empty() {
+        let remaining = SYNC_TIMEOUT.saturating_sub(start.elapsed());
+        let line = receiver
+            .recv_timeout(remaining)
+            .unwrap_or_else(|_| panic!("node never logged {:?}", missing));
+        missing.retain(|expected| !line.contains(expected));
+    }
+    // Give sled a chance to flush before the node is killed.
+    thread::sleep(Duration::from_secs(2));
+}
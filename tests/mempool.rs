@@ -0,0 +1,204 @@
+mod common;
+
+use common::{
+    balance, copy_dir, create_wallet, run, run_err, start_node, start_node_with_env,
+    wait_for_listener, wait_for_log, wait_for_logs, work_dir, CENTRAL_ADDR, NETWORK,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Funds two senders on the central node and copies the chain and wallets to
+// a second directory to make transactions from, as the node holds the
+// database. Returns the wallet directory and the sender addresses.
+fn fund_two_senders(central_dir: &Path, name: &str) -> (PathBuf, String, String) {
+    let wallet_dir = work_dir(name);
+    let sender = create_wallet(central_dir);
+    let other_sender = create_wallet(central_dir);
+    run(central_dir, &["createblockchain", sender.as_str()]);
+    let send = [
+        "send", "--from", sender.as_str(), "--to", other_sender.as_str(), "--amount", "5",
+        "--mine",
+    ];
+    run(central_dir, &send);
+    copy_dir(&central_dir.join("data"), &wallet_dir.join("data"));
+    fs::copy(central_dir.join("wallet.dat"), wallet_dir.join("wallet.dat")).unwrap();
+    (wallet_dir, sender, other_sender)
+}
+
+// Arguments to send a transaction to the central node
+fn submit<'a>(from: &'a str, to: &'a str, amount: &'a str, fee: &'a str) -> [&'a str; 11] {
+    [
+        "send", "--from", from, "--to", to, "--amount", amount, "--fee", fee, "--connect",
+        CENTRAL_ADDR,
+    ]
+}
+
+#[test]
+fn mining_node_claims_the_fees_of_relayed_transactions() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("fees_central");
+    let (wallet_dir, sender, other_sender) = fund_two_senders(&central_dir, "fees_wallet");
+    let receiver = create_wallet(&central_dir);
+    let miner = create_wallet(&central_dir);
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &["--miner", miner.as_str()]);
+    wait_for_listener(CENTRAL_ADDR);
+    run(&wallet_dir, &submit(&sender, &receiver, "1", "3"));
+    run(&wallet_dir, &submit(&other_sender, &receiver, "1", "2"));
+    wait_for_log(&mut central, "is mined!");
+
+    drop(central);
+    assert_eq!(balance(&central_dir, miner.as_str()), 15);
+    assert_eq!(balance(&central_dir, receiver.as_str()), 2);
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(wallet_dir);
+}
+
+#[test]
+fn memory_pool_turns_away_transactions_with_a_reason() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("policy_central");
+    let (wallet_dir, sender, other_sender) = fund_two_senders(&central_dir, "policy_wallet");
+    let receiver = create_wallet(&central_dir);
+    // Does not know about the transactions sent from the wallet directory
+    let stale_dir = work_dir("policy_stale");
+    copy_dir(&wallet_dir, &stale_dir);
+
+    // Room for a single transaction
+    let envs = [("MAX_MEMPOOL_SIZE", "500")];
+    let mut central = start_node_with_env(&central_dir, CENTRAL_ADDR, &envs, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+
+    let stderr = run_err(&wallet_dir, &submit(&sender, &receiver, "1", "0"));
+    assert!(stderr.contains("below the minimum relay fee of 1"), "{}", stderr);
+
+    run(&wallet_dir, &submit(&sender, &receiver, "1", "1"));
+    // A higher fee rate evicts the first transaction
+    run(&wallet_dir, &submit(&other_sender, &receiver, "1", "3"));
+    let stderr = run_err(&wallet_dir, &submit(&sender, &receiver, "2", "1"));
+    assert!(stderr.contains("does not fit in the full memory pool"), "{}", stderr);
+
+    let stderr = run_err(&stale_dir, &submit(&other_sender, &receiver, "2", "3"));
+    assert!(stderr.contains("conflicts with"), "{}", stderr);
+    wait_for_log(&mut central, "Evicted transaction");
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(wallet_dir);
+    let _ = fs::remove_dir_all(stale_dir);
+}
+
+#[test]
+fn unconfirmed_change_can_be_spent_before_it_is_mined() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("chain_central");
+    let (wallet_dir, _, other_sender) = fund_two_senders(&central_dir, "chain_wallet");
+    let receiver = create_wallet(&central_dir);
+    let miner = create_wallet(&central_dir);
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &["--miner", miner.as_str()]);
+    wait_for_listener(CENTRAL_ADDR);
+    // The only confirmed output of 5 is spent by the first transaction, the
+    // second one spends its change of 3
+    run(&wallet_dir, &submit(&other_sender, &receiver, "1", "1"));
+    run(&wallet_dir, &submit(&other_sender, &receiver, "1", "1"));
+    let stderr = run_err(&wallet_dir, &submit(&other_sender, &receiver, "1", "1"));
+    assert!(stderr.contains("has 1 but 2 are needed"), "{}", stderr);
+    // Both go into the same block, the parent first
+    wait_for_log(&mut central, "is mined!");
+
+    drop(central);
+    assert_eq!(balance(&central_dir, receiver.as_str()), 2);
+    assert_eq!(balance(&central_dir, other_sender.as_str()), 1);
+    assert_eq!(balance(&central_dir, miner.as_str()), 12);
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(wallet_dir);
+}
+
+#[test]
+fn memory_pool_is_saved_and_revalidated_across_restarts() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("persist_central");
+    let (wallet_dir, sender, other_sender) = fund_two_senders(&central_dir, "persist_wallet");
+    let receiver = create_wallet(&central_dir);
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    run(&wallet_dir, &submit(&sender, &receiver, "1", "1"));
+    run(&wallet_dir, &submit(&other_sender, &receiver, "1", "1"));
+    wait_for_log(&mut central, "Saved the memory pool with 2 transactions");
+    drop(central);
+
+    // While the node is down a block spends the output of the first transaction.
+    let send = [
+        "send", "--from", sender.as_str(), "--to", receiver.as_str(), "--amount", "2", "--mine",
+    ];
+    run(&central_dir, &send);
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_logs(
+        &mut central,
+        &[
+            "Loaded 1 transactions into the memory pool, 0 expired, 1 no longer valid",
+            "Saved the memory pool with 1 transactions",
+        ],
+    );
+    drop(central);
+
+    let envs = [("MEMPOOL_EXPIRY", "0")];
+    let mut central = start_node_with_env(&central_dir, CENTRAL_ADDR, &envs, &[]);
+    wait_for_log(&mut central, "Loaded 0 transactions into the memory pool, 1 expired");
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(wallet_dir);
+}
+
+// Id of the transaction made by send or bumpfee
+fn txid_of(stdout: &str) -> String {
+    let mut ids = stdout.lines().filter_map(|line| line.strip_prefix("Transaction id: "));
+    String::from(ids.next().unwrap())
+}
+
+#[test]
+fn replaceable_transactions_are_replaced_with_their_descendants() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("rbf_central");
+    let (wallet_dir, sender, other_sender) = fund_two_senders(&central_dir, "rbf_wallet");
+    let receiver = create_wallet(&central_dir);
+    // Does not know about the transactions sent from the wallet directory
+    let stale_dir = work_dir("rbf_stale");
+    copy_dir(&wallet_dir, &stale_dir);
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let final_txid = txid_of(&run(&wallet_dir, &submit(&sender, &receiver, "1", "1")));
+    let stderr = run_err(&wallet_dir, &["bumpfee", final_txid.as_str(), "--connect", CENTRAL_ADDR]);
+    assert!(stderr.contains("was not sent as replaceable"), "{}", stderr);
+    let mut conflicting = submit(&sender, &receiver, "1", "3").to_vec();
+    conflicting.push("--rbf");
+    let stderr = run_err(&stale_dir, &conflicting);
+    assert!(stderr.contains("which is not replaceable"), "{}", stderr);
+
+    // The child spends the change of its parent and goes with it
+    let mut send = submit(&other_sender, &receiver, "1", "1").to_vec();
+    send.push("--rbf");
+    let parent = txid_of(&run(&wallet_dir, &send));
+    let child = txid_of(&run(&wallet_dir, &submit(&other_sender, &receiver, "1", "1")));
+    let no_bump = ["bumpfee", parent.as_str(), "--fee", "1", "--connect", CENTRAL_ADDR];
+    let stderr = run_err(&wallet_dir, &no_bump);
+    assert!(stderr.contains("already pays a fee of 1"), "{}", stderr);
+    let low_bump = ["bumpfee", parent.as_str(), "--fee", "2", "--connect", CENTRAL_ADDR];
+    let stderr = run_err(&wallet_dir, &low_bump);
+    assert!(stderr.contains("pays a fee of 2 not above the fee of 2"), "{}", stderr);
+
+    let bump = ["bumpfee", parent.as_str(), "--connect", CENTRAL_ADDR];
+    let replacement = txid_of(&run(&wallet_dir, &bump));
+    let replaced = |txid: &str| {
+        format!("Replaced transaction {} in the memory pool with {}", txid, replacement)
+    };
+    wait_for_logs(&mut central, &[replaced(&parent).as_str(), replaced(&child).as_str()]);
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(wallet_dir);
+    let _ = fs::remove_dir_all(stale_dir);
+}
@@ -0,0 +1,34 @@
+use rust_blockchain::utils::sha256_digest;
+use rust_blockchain::{merkle_proof, merkle_root};
+
+fn txids(count: u8) -> Vec<Vec<u8>> {
+    (0..count).map(|i| sha256_digest(&[i])).collect()
+}
+
+#[test]
+fn single_transaction_root_is_its_txid() {
+    let txids = txids(1);
+    assert_eq!(merkle_root(&txids), txids[0]);
+    assert!(merkle_proof(&txids, &txids[0]).unwrap().verify(&txids[0]));
+}
+
+#[test]
+fn every_txid_has_a_valid_proof() {
+    for count in 2..=9 {
+        let txids = txids(count);
+        let root = merkle_root(&txids);
+        for txid in &txids {
+            let proof = merkle_proof(&txids, txid).unwrap();
+            assert_eq!(proof.get_txid(), txid.as_slice());
+            assert!(proof.verify(&root), "proof failed for {} txids", count);
+        }
+    }
+}
+
+#[test]
+fn proof_does_not_verify_against_another_root() {
+    let txids = txids(5);
+    let proof = merkle_proof(&txids, &txids[3]).unwrap();
+    assert!(!proof.verify(&merkle_root(&txids[..4])));
+    assert!(merkle_proof(&txids, &sha256_digest(b"unknown")).is_none());
+}
@@ -0,0 +1,182 @@
+mod common;
+
+use common::{
+    copy_dir, create_wallet, run, start_node, start_node_with_env, tip_hash, wait_for_listener,
+    wait_for_log, work_dir, CENTRAL_ADDR, NETWORK, PEER_ADDR,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+#[test]
+fn peer_syncs_block_from_central_node() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("central");
+    let peer_dir = work_dir("peer");
+
+    let miner = create_wallet(&central_dir);
+    let receiver = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+
+    // Both nodes share the genesis block, only the central node mines on top of it.
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+    run(
+        &central_dir,
+        &["send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "1", "--mine"],
+    );
+    let mined_hash = tip_hash(&central_dir);
+
+    let central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut peer = start_node(&peer_dir, PEER_ADDR, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut peer, format!("Added block {}", mined_hash).as_str());
+
+    drop(peer);
+    drop(central);
+    assert_eq!(tip_hash(&peer_dir), mined_hash);
+    assert!(!run(&peer_dir, &["printchain"]).contains("PoW valid: false"));
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+}
+
+#[test]
+fn central_node_reorganises_to_longer_branch() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("reorg_central");
+    let peer_dir = work_dir("reorg_peer");
+
+    let miner = create_wallet(&central_dir);
+    let receiver = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+    fs::copy(central_dir.join("wallet.dat"), peer_dir.join("wallet.dat")).unwrap();
+
+    // The central node mines one block, the peer mines a competing branch of two.
+    let send = [
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "1", "--mine",
+    ];
+    run(&central_dir, &send);
+    let orphaned_hash = tip_hash(&central_dir);
+    run(&peer_dir, &send);
+    run(&peer_dir, &send);
+    let best_hash = tip_hash(&peer_dir);
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let peer = start_node(&peer_dir, PEER_ADDR, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut central, format!("Added block {}", best_hash).as_str());
+
+    drop(peer);
+    drop(central);
+    assert_eq!(tip_hash(&central_dir), best_hash);
+    assert!(!run(&central_dir, &["printchain"]).contains(orphaned_hash.as_str()));
+    let balance = run(&central_dir, &["getbalance", receiver.as_str()]);
+    assert!(balance.trim().ends_with(": 2"), "unexpected balance: {}", balance);
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+}
+
+#[test]
+fn peer_syncs_several_blocks_through_headers() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("headers_central");
+    let peer_dir = work_dir("headers_peer");
+
+    let miner = create_wallet(&central_dir);
+    let receiver = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+    let send = [
+        "send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "1", "--mine",
+    ];
+    for _ in 0..3 {
+        run(&central_dir, &send);
+    }
+    let best_hash = tip_hash(&central_dir);
+
+    let central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut peer = start_node(&peer_dir, PEER_ADDR, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut peer, format!("Added block {}", best_hash).as_str());
+
+    drop(peer);
+    drop(central);
+    assert_eq!(tip_hash(&peer_dir), best_hash);
+    assert_eq!(run(&peer_dir, &["printchain"]), run(&central_dir, &["printchain"]));
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+}
+
+#[test]
+fn peers_register_the_connection_after_the_handshake() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("handshake_central");
+    let peer_dir = work_dir("handshake_peer");
+
+    let miner = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut peer = start_node(&peer_dir, PEER_ADDR, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut central, format!("Connected to peer {}", PEER_ADDR).as_str());
+    wait_for_log(&mut peer, format!("Connected to peer {}", CENTRAL_ADDR).as_str());
+
+    drop(peer);
+    drop(central);
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+}
+
+#[test]
+fn packages_before_the_handshake_are_rejected() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = work_dir("no_handshake");
+    let miner = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let mut node = start_node_with_env(&dir, CENTRAL_ADDR, &[("WIRE_CODEC", "json")], &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut stream = TcpStream::connect(CENTRAL_ADDR).unwrap();
+    let get_blocks = r#"{"GetBlocks":{"addr_from":"127.0.0.1:2009","locator":[]}}"#;
+    stream.write_all(get_blocks.as_bytes()).unwrap();
+    wait_for_log(&mut node, "getblocks package before the handshake");
+
+    drop(node);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn replies_go_back_on_the_connection_the_request_came_in_on() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = work_dir("reply_route");
+    let miner = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let _node = start_node_with_env(&dir, CENTRAL_ADDR, &[("WIRE_CODEC", "json")], &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    // Nothing listens on the addresses the packages claim to come from
+    let mut stream = TcpStream::connect(CENTRAL_ADDR).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let packages = concat!(
+        r#"{"Version":{"addr_from":"127.0.0.1:2009","version":1,"best_height":0}}"#,
+        r#"{"VerAck":{"addr_from":"127.0.0.1:2009"}}"#,
+        r#"{"Ping":{"addr_from":"127.0.0.1:2010","nonce":42}}"#
+    );
+    stream.write_all(packages.as_bytes()).unwrap();
+    let mut received = String::new();
+    let mut buf = [0; 1024];
+    while !received.contains(r#""Pong""#) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "connection closed after {}", received);
+        received.push_str(String::from_utf8_lossy(&buf[..n]).as_ref());
+    }
+
+    drop(stream);
+    let _ = fs::remove_dir_all(dir);
+}
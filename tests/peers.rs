@@ -0,0 +1,89 @@
+mod common;
+
+use common::{
+    copy_dir, create_wallet, run, run_err, start_node, start_node_with_env, wait_for_listener,
+    wait_for_log, work_dir, CENTRAL_ADDR, NETWORK, PEER_ADDR,
+};
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+
+const THIRD_ADDR: &str = "127.0.0.1:2003";
+
+#[test]
+fn nodes_learn_peers_from_gossip_and_remember_them() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("gossip_central");
+    let peer_dir = work_dir("gossip_peer");
+    let third_dir = work_dir("gossip_third");
+
+    let miner = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+    copy_dir(&central_dir.join("data"), &third_dir.join("data"));
+
+    let mut central = start_node(&central_dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let peer = start_node(&peer_dir, PEER_ADDR, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut central, format!("Connected to peer {}", PEER_ADDR).as_str());
+
+    // The third node only knows the seed and learns about the peer from it.
+    let mut third = start_node(&third_dir, THIRD_ADDR, &["--seed", CENTRAL_ADDR]);
+    wait_for_log(&mut third, format!("Connected to peer {}", PEER_ADDR).as_str());
+    drop(third);
+
+    let peers = run(&third_dir, &["listpeers"]);
+    for addr in [CENTRAL_ADDR, PEER_ADDR] {
+        assert!(
+            peers.contains(format!("{} last seen", addr).as_str()),
+            "{} missing from {}",
+            addr,
+            peers
+        );
+    }
+
+    // Restarted without a seed, it reconnects to the stored peers.
+    let mut third = start_node(&third_dir, THIRD_ADDR, &[]);
+    wait_for_log(&mut third, format!("Connected to peer {}", CENTRAL_ADDR).as_str());
+
+    drop(third);
+    drop(peer);
+    drop(central);
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+    let _ = fs::remove_dir_all(third_dir);
+}
+
+#[test]
+fn peers_sending_malformed_blocks_are_banned() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = work_dir("ban");
+    let miner = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let mut node = start_node_with_env(&dir, CENTRAL_ADDR, &[("WIRE_CODEC", "json")], &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut stream = TcpStream::connect(CENTRAL_ADDR).unwrap();
+    let handshake = concat!(
+        r#"{"Version":{"addr_from":"127.0.0.1:2009","version":1,"best_height":0}}"#,
+        r#"{"VerAck":{"addr_from":"127.0.0.1:2009"}}"#
+    );
+    stream.write_all(handshake.as_bytes()).unwrap();
+    let block = r#"{"Block":{"addr_from":"127.0.0.1:2009","block":[1,2,3]}}"#;
+    stream.write_all(block.as_bytes()).unwrap();
+    // The ban holds against the IP the peer connects from, whatever it announces
+    wait_for_log(&mut node, "Banned peer 127.0.0.1:2009 at 127.0.0.1");
+    TcpStream::connect(CENTRAL_ADDR).unwrap();
+    wait_for_log(&mut node, "Refused connection from banned peer 127.0.0.1");
+    drop(node);
+
+    let bans = run(&dir, &["listbanned"]);
+    assert!(bans.contains("127.0.0.1 banned until"), "{}", bans);
+    let err = run_err(&dir, &["clearbanned", "--addr", "127.0.0.1:2009"]);
+    assert!(err.contains("invalid peer IP"), "{}", err);
+    let cleared = run(&dir, &["clearbanned"]);
+    assert!(cleared.contains("Cleared 1 bans."), "{}", cleared);
+    assert!(run(&dir, &["listbanned"]).is_empty());
+
+    let _ = fs::remove_dir_all(dir);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use common::{
+    copy_dir, create_wallet, run, start_node, start_node_with_env, tip_hash, wait_for_listener,
+    wait_for_log, work_dir, CENTRAL_ADDR, NETWORK, PEER_ADDR,
+};
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+
+const TESTNET_MAGIC: [u8; 4] = [0x0b, 0x11, 0x09, 0x07];
+const MAINNET_MAGIC: [u8; 4] = [0xf9, 0xbe, 0xb4, 0xd9];
+
+// magic | command padded to 12 bytes | payload length | payload checksum
+fn frame_header(magic: [u8; 4], command: &str, length: u32) -> Vec<u8> {
+    let mut header = magic.to_vec();
+    let mut command_bytes = [0u8; 12];
+    command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+    header.extend(command_bytes);
+    header.extend(length.to_le_bytes());
+    header.extend([0u8; 4]);
+    header
+}
+
+#[test]
+fn node_rejects_frames_from_another_network() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = work_dir("wire_magic");
+    let miner = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let mut node = start_node(&dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut stream = TcpStream::connect(CENTRAL_ADDR).unwrap();
+    stream.write_all(&frame_header(TESTNET_MAGIC, "version", 0)).unwrap();
+    wait_for_log(&mut node, "unexpected network magic");
+
+    drop(node);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn node_rejects_oversized_frames() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = work_dir("wire_size");
+    let miner = create_wallet(&dir);
+    run(&dir, &["createblockchain", miner.as_str()]);
+
+    let mut node = start_node(&dir, CENTRAL_ADDR, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut stream = TcpStream::connect(CENTRAL_ADDR).unwrap();
+    stream.write_all(&frame_header(MAINNET_MAGIC, "block", u32::MAX)).unwrap();
+    wait_for_log(&mut node, "exceeds the maximum message size");
+
+    drop(node);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn nodes_sync_with_the_json_codec() {
+    let _network = NETWORK.lock().unwrap_or_else(|e| e.into_inner());
+    let central_dir = work_dir("json_central");
+    let peer_dir = work_dir("json_peer");
+
+    let miner = create_wallet(&central_dir);
+    let receiver = create_wallet(&central_dir);
+    run(&central_dir, &["createblockchain", miner.as_str()]);
+    copy_dir(&central_dir.join("data"), &peer_dir.join("data"));
+    run(
+        &central_dir,
+        &["send", "--from", miner.as_str(), "--to", receiver.as_str(), "--amount", "1", "--mine"],
+    );
+    let mined_hash = tip_hash(&central_dir);
+
+    let json = [("WIRE_CODEC", "json")];
+    let central = start_node_with_env(&central_dir, CENTRAL_ADDR, &json, &[]);
+    wait_for_listener(CENTRAL_ADDR);
+    let mut peer = start_node_with_env(&peer_dir, PEER_ADDR, &json, &["--connect", CENTRAL_ADDR]);
+    wait_for_log(&mut peer, format!("Added block {}", mined_hash).as_str());
+
+    drop(peer);
+    drop(central);
+    assert_eq!(tip_hash(&peer_dir), mined_hash);
+
+    let _ = fs::remove_dir_all(central_dir);
+    let _ = fs::remove_dir_all(peer_dir);
+}